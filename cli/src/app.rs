@@ -0,0 +1,1556 @@
+// src/app.rs
+
+use std::{collections::{HashMap, HashSet}, fs, io::{self, Stdout}, sync::mpsc::Sender};
+use crossterm::{
+    execute,
+    terminal::{Clear, ClearType},
+    event::{Event, KeyCode, KeyEvent},
+    cursor, style::{Print, PrintStyledContent, ContentStyle},
+};
+use crate::config::Profile;
+use crate::e2e::{self, Identity, Lock, Opened, Published};
+use crate::events::Network;
+use crate::signing::{self, Signature};
+use crate::export::{self, Format, FORMATS};
+use crate::history::History;
+use crate::layout::{display_width, hanging_indent, layout_message, truncate};
+use crate::mention::{self, mentions, Completion};
+use crate::notify::{Level, Notifier, QuietHours};
+use crate::picker::{Item, Picker};
+use crate::request::{find_user, get_user_by_id, remember_user, set_users, user_names, ApiError, Chat, Client, Room, User};
+use crate::search::{snippet, Query};
+use crate::status::{Connection, Status};
+use crate::store::Store;
+use crate::sync::{SyncCommand, SyncEvent};
+use crate::theme::Theme;
+
+/// A row in the history pane: a chat message, or a notice from the client itself
+pub enum Entry {
+    Chat(Chat, Lock, Signature),
+    Notice(String),
+    Error(String),
+}
+
+/// One conversation, the chat log of a user or room on the server plus our own notices
+pub struct Buffer {
+    pub name: String,
+    pub room: bool,
+    pub thread: bool, // The direct messages between us and the user, rather than everything sent to them
+    pub entries: Vec<Entry>,
+    chat_entries: Vec<usize>, // Where each chat of the server log sits in `entries`
+    synced: usize,            // How many chats of the server log are already in `entries`
+    read: usize,              // How many chats of the server log have been seen
+    divider: Option<usize>,   // Entry the "new messages" divider is drawn above
+    scroll: usize,            // Rows scrolled up from the bottom of the history pane
+    loaded: bool,             // Whether the server log has been fetched at least once
+    missing: bool,            // Whether the server said this user does not exist
+    orphans: Vec<usize>,      // Chats from before a server restart, waiting for their replayed copies
+    raw: Vec<Chat>,           // The server log as last applied, to check signatures again once a key turns up
+}
+
+impl Buffer {
+    fn new(name: String) -> Buffer {
+        Buffer {
+            name,
+            room: false,
+            thread: false,
+            entries: Vec::new(),
+            chat_entries: Vec::new(),
+            synced: 0,
+            read: 0,
+            divider: None,
+            scroll: 0,
+            loaded: false,
+            missing: false,
+            orphans: Vec::new(),
+            raw: Vec::new(),
+        }
+    }
+
+    pub fn unread(&self) -> usize {
+        self.synced.saturating_sub(self.read)
+    }
+
+    // Marks everything read, first putting the divider above the first unread chat when asked to
+    fn mark_read(&mut self, divider: bool) {
+        if divider && self.divider.is_none() && self.read < self.synced {
+            self.divider = self.chat_entries.get(self.read).copied();
+        }
+        self.read = self.synced;
+    }
+}
+
+// Text of the divider drawn above the first unread message
+const DIVIDER: &str = " new messages ";
+
+// Messages shown above and below the selected one in a picker's preview
+const PREVIEW_CONTEXT: usize = 1;
+
+// What the history pane shows on one row
+enum Row {
+    Text(String, ContentStyle, String, ContentStyle),
+    Divider,
+}
+
+// The active buffer laid out for the history pane
+struct Laid {
+    rows: Vec<Row>,
+    divider: Option<usize>, // Row of the "new messages" divider
+    starts: Vec<usize>,     // First row of each entry
+}
+
+/// What the input line is taken up with
+enum Mode {
+    Compose,
+    Ask { label: String, hidden: bool, answer: String, then: Asked },
+    Confirm { question: String, then: Confirmed },
+}
+
+/// What a line typed at a prompt is for
+pub enum Asked {
+    Switch,
+    Search,
+    Secret(String), // For logging in with this name
+}
+
+/// What a yes to a question goes ahead with
+pub enum Confirmed {
+    Paste(String),
+}
+
+/// How a call the network task made for us went
+pub enum Done {
+    Sent { to: String, entry: usize, posted: String, result: Result<(), ApiError> },
+    Published { keys: Published, result: Result<bool, ApiError> }, // Whether the keys out there are ours
+    Fetched { id: String, query: Query, result: Result<(Vec<Chat>, bool), ApiError> }, // The chats, and whether they are a DM thread
+    LoggedIn { name: String, result: Result<(), ApiError> },
+    LookedUp { id: String, result: Result<User, ApiError> },
+    Replayed(Result<usize, String>),
+    Rooms { listed: bool, result: Result<Vec<Room>, ApiError> }, // Only shown when `listed`, else just reopened
+    Joined { name: String, result: Result<Room, ApiError> },
+    Left { name: String, result: Result<Room, ApiError> },
+}
+
+/// Everything the TUI shows, and the handles it needs to talk to the server
+pub struct App {
+    pub stdout: Stdout,
+    pub cols: u16,
+    pub input_line: u16,
+    pub theme: Theme,
+    pub me: User,
+    pub message: String, // The composer
+    pub completion: Option<Completion>,
+    pub picker: Option<Picker>, // Shown in place of the history pane while open
+    pub buffers: HashMap<String, Buffer>,
+    pub order: Vec<String>, // Buffer ids in the order they were opened
+    pub active: String,
+    pub focused: bool, // Whether the terminal window has focus
+    status: Status,
+    pending: Vec<(String, String, usize)>, // Sent chats still waiting to come back from the server, with their entry
+    store: Store,
+    history: History,
+    identity: Option<Identity>,
+    announced: HashMap<String, Published>, // The keys each conversation published last
+    profiles: HashMap<String, String>,     // The key announcement in each user's profile when last seen
+    looked_up: HashSet<String>,            // Users whose chat log was searched for keys already
+    notifier: Notifier,
+    mode: Mode,
+    net: Network, // Every call to the server goes through here, so none of them hold up the TUI
+    sync: Sender<SyncCommand>,
+}
+
+// Ids are long hashes, the first few characters are enough to tell them apart on screen
+pub fn short_id(id: &str) -> String {
+    id.chars().take(8).collect()
+}
+
+impl App {
+    pub fn new(me: User, profile: &Profile, net: Network, theme: Theme, cols: u16, rows: u16, sync: Sender<SyncCommand>) -> App {
+        let status = Status {
+            username: me.name.clone(),
+            short_id: short_id(&me.id),
+            profile: profile.name.clone(),
+            connection: Connection::Connecting,
+            peer: None,
+            unread: 0,
+            sealed: false,
+            busy: 0,
+            frame: 0,
+        };
+
+        // Our own chat log is the first buffer, it holds what others send us
+        let mut buffers = HashMap::new();
+        buffers.insert(me.id.clone(), Buffer::new(me.name.clone()));
+        let mut order = vec![me.id.clone()];
+
+        // Reopen the conversations from last time so their unread counts show up
+        let store = Store::open(profile);
+
+        // Without a key pair everything still works, only sealed chats can not be read or sent
+        let identity = match Identity::load(&me.id) {
+            Ok(identity) => Some(identity),
+            Err(error) => {
+                let inbox = buffers.get_mut(&me.id).unwrap();
+                inbox.entries.push(Entry::Error(format!("No encryption key pair: {}", error)));
+                None
+            }
+        };
+        for id in store.open.iter().filter(|id| **id != me.id) {
+            buffers.insert(id.clone(), Buffer::new(short_id(id)));
+            order.push(id.clone());
+            let _ = sync.send(SyncCommand::Watch(id.clone()));
+        }
+
+        let mut app = App {
+            stdout: io::stdout(),
+            cols,
+            input_line: rows.saturating_sub(2).max(3),
+            theme,
+            active: me.id.clone(),
+            order,
+            me,
+            message: String::new(),
+            completion: None,
+            picker: None,
+            buffers,
+            status,
+            pending: Vec::new(),
+            store,
+            history: History::open(profile),
+            identity,
+            announced: HashMap::new(),
+            profiles: HashMap::new(),
+            looked_up: HashSet::new(),
+            notifier: Notifier::from_env(),
+            focused: true,
+            mode: Mode::Compose,
+            net,
+            sync,
+        };
+        // The server knows which rooms we are in, their buffers open once it answers
+        app.run(|client| Done::Rooms { listed: false, result: client.get_rooms() });
+        app
+    }
+
+    /// Clears the screen and draws everything
+    pub fn draw(&mut self) -> io::Result<()> {
+        execute!(self.stdout, Clear(ClearType::All))?;
+        self.paint_status()?;
+        self.paint_history()?;
+        self.paint_frame()?;
+        self.draw_input()
+    }
+
+    /// Reflows everything to a new terminal size
+    pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
+        self.cols = cols;
+        self.input_line = rows.saturating_sub(2).max(3);
+        self.draw()
+    }
+
+    pub fn draw_status(&mut self) -> io::Result<()> {
+        self.paint_status()?;
+        self.draw_input()
+    }
+
+    pub fn draw_history(&mut self) -> io::Result<()> {
+        self.paint_history()?;
+        self.draw_input()
+    }
+
+    // Draws the separator above the input line with the buffer list and unread counts set into it
+    fn paint_frame(&mut self) -> io::Result<()> {
+        let width = self.cols.saturating_sub(1) as usize;
+        let mut room = width.saturating_sub(2);
+        execute!(self.stdout, cursor::MoveTo(0, self.input_line - 1), PrintStyledContent(self.theme.border.apply("+-")))?;
+
+        for id in &self.order {
+            let buffer = &self.buffers[id];
+            let label = match buffer.unread() {
+                0 => format!("[{}]", buffer.name),
+                unread => format!("[{} ({})]", buffer.name, unread),
+            };
+            let style = if *id == self.active {
+                self.theme.own_name
+            } else if buffer.unread() > 0 {
+                self.theme.warn
+            } else {
+                self.theme.border
+            };
+
+            let label = truncate(&label, room.saturating_sub(1));
+            room = room.saturating_sub(display_width(&label) + 1);
+            execute!(self.stdout, PrintStyledContent(style.apply(label)), PrintStyledContent(self.theme.border.apply("-")))?;
+        }
+
+        // Dash out the rest of the top of the input line
+        let rest = "-".repeat(room.saturating_sub(1)) + "+";
+        execute!(self.stdout, PrintStyledContent(self.theme.border.apply(rest)))
+    }
+
+    fn paint_status(&mut self) -> io::Result<()> {
+        self.status.unread = self.buffers.values().map(Buffer::unread).sum();
+        self.status.sealed = self.store.encrypted.contains(&self.active);
+        self.status.peer = if self.active == self.me.id {
+            None
+        } else {
+            self.buffers.get(&self.active).map(|buffer| buffer.name.clone())
+        };
+        self.status.draw(&mut self.stdout, self.cols, &self.theme)
+    }
+
+    // Lays out the active buffer for the history pane
+    fn history_rows(&self) -> Laid {
+        let width = self.cols.saturating_sub(3) as usize;
+        let theme = &self.theme;
+        let buffer = &self.buffers[&self.active];
+
+        let mut rows = Vec::new();
+        let mut divider = None;
+        let mut starts = Vec::new();
+        for (index, entry) in buffer.entries.iter().enumerate() {
+            if buffer.divider == Some(index) {
+                divider = Some(rows.len());
+                rows.push(Row::Divider);
+            }
+            starts.push(rows.len());
+
+            let (prefix, prefix_style, text, text_style) = match entry {
+                Entry::Chat(chat, lock, signature) => {
+                    let (name_style, text_style) = if chat.user_id == self.me.id {
+                        (theme.own_name, theme.own_text)
+                    } else if mentions(&chat.chat, &self.me.name) {
+                        // Messages that mention us stand out from the rest
+                        (theme.name_style(&chat.user_id), theme.mention)
+                    } else {
+                        (theme.name_style(&chat.user_id), theme.text)
+                    };
+                    let (badge, text_style) = match lock {
+                        Lock::Plain => ("", text_style),
+                        Lock::Sealed => (" [e2e]", text_style),
+                        Lock::KeyChanged => (" [key changed]", theme.warn),
+                        Lock::Unreadable => (" [sealed]", theme.system),
+                    };
+                    let (signed, name_style, text_style) = match signature {
+                        Signature::Verified => (" \u{2713}", name_style, text_style),
+                        Signature::Unverified => (" ?", name_style, text_style),
+                        Signature::Forged => (" [forged]", theme.error, theme.error),
+                    };
+                    (format!("{}{}{}: ", chat.user_name, signed, badge), name_style, &chat.chat, text_style)
+                }
+                Entry::Notice(text) => ("* ".to_string(), theme.system, text, theme.system),
+                Entry::Error(text) => ("! ".to_string(), theme.error, text, theme.error),
+            };
+
+            let indent = " ".repeat(hanging_indent(&prefix, width));
+            for (i, row) in layout_message(&prefix, text, width).into_iter().enumerate() {
+                let lead = if i == 0 { truncate(&prefix, width) } else { indent.clone() };
+                rows.push(Row::Text(lead, prefix_style, row, text_style));
+            }
+        }
+        Laid { rows, divider, starts }
+    }
+
+    // Redraws the history pane between the status line and the input separator, newest message at the bottom
+    fn paint_history(&mut self) -> io::Result<()> {
+        if self.picker.is_some() {
+            return self.paint_picker();
+        }
+
+        let width = self.cols.saturating_sub(3) as usize;
+        let height = self.input_line.saturating_sub(2) as usize;
+        let rows = self.history_rows().rows;
+
+        // Keep only the rows that fit, shifted up by however far the buffer is scrolled
+        let scroll = self.buffers[&self.active].scroll.min(rows.len().saturating_sub(height));
+        let skip = rows.len().saturating_sub(height + scroll);
+
+        for line in 0..height {
+            execute!(self.stdout, cursor::MoveTo(1, line as u16 + 1), Clear(ClearType::UntilNewLine))?;
+            match rows.get(skip + line) {
+                Some(Row::Text(lead, lead_style, text, text_style)) => {
+                    execute!(self.stdout, PrintStyledContent(lead_style.apply(lead)), PrintStyledContent(text_style.apply(text)))?;
+                }
+                Some(Row::Divider) => {
+                    let dashes = "\u{2014}".repeat(width.saturating_sub(DIVIDER.len()) / 2);
+                    let divider = format!("{}{}{}", dashes, DIVIDER, dashes);
+                    execute!(self.stdout, PrintStyledContent(self.theme.warn.apply(divider)))?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Draws the open picker over the history pane, keeping the selected item in view
+    fn paint_picker(&mut self) -> io::Result<()> {
+        let width = self.cols.saturating_sub(3) as usize;
+        let height = self.input_line.saturating_sub(2) as usize;
+        let Some(picker) = &self.picker else { return Ok(()) };
+
+        let title = format!("{} ({} found, Enter to jump, Esc to close)", picker.title, picker.items.len());
+        execute!(self.stdout, cursor::MoveTo(1, 1), Clear(ClearType::UntilNewLine), PrintStyledContent(self.theme.system.apply(truncate(&title, width))))?;
+
+        // Leave room under the list for the selected message and its neighbours when there is space
+        let preview = if height >= 10 { PREVIEW_CONTEXT * 2 + 1 } else { 0 };
+        let shown = height.saturating_sub(1 + if preview > 0 { preview + 1 } else { 0 });
+        let skip = (picker.selected + 1).saturating_sub(shown);
+        for line in 0..shown {
+            execute!(self.stdout, cursor::MoveTo(1, line as u16 + 2), Clear(ClearType::UntilNewLine))?;
+            if let Some(item) = picker.items.get(skip + line) {
+                let style = if skip + line == picker.selected { self.theme.status } else { self.theme.text };
+                execute!(self.stdout, PrintStyledContent(style.apply(truncate(&item.text, width))))?;
+            }
+        }
+        if preview == 0 {
+            return Ok(());
+        }
+
+        let top = shown as u16 + 2;
+        execute!(self.stdout, cursor::MoveTo(1, top), Clear(ClearType::UntilNewLine), PrintStyledContent(self.theme.border.apply("-".repeat(width))))?;
+        let selected = picker.current().and_then(|item| Some((item.buffer.clone(), item.entry?)));
+        for line in 0..preview {
+            execute!(self.stdout, cursor::MoveTo(1, top + 1 + line as u16), Clear(ClearType::UntilNewLine))?;
+            let Some((buffer, entry)) = &selected else { continue };
+            let Some(index) = (entry + line).checked_sub(PREVIEW_CONTEXT) else { continue };
+            let Some(Entry::Chat(chat, ..)) = self.buffers.get(buffer).and_then(|buffer| buffer.entries.get(index)) else { continue };
+
+            let style = if index == *entry { self.theme.mention } else { self.theme.system };
+            let text = format!("{}: {}", chat.user_name, chat.chat.replace('\n', " "));
+            execute!(self.stdout, PrintStyledContent(style.apply(truncate(&text, width))))?;
+        }
+        Ok(())
+    }
+
+    /// Opens a picker in place of the history pane
+    pub fn open_picker(&mut self, picker: Picker) -> io::Result<()> {
+        self.picker = Some(picker);
+        self.draw_history()
+    }
+
+    pub fn close_picker(&mut self) -> io::Result<()> {
+        self.picker = None;
+        self.draw_history()
+    }
+
+    /// Moves the picker selection up or down
+    pub fn picker_step(&mut self, step: isize) -> io::Result<()> {
+        if let Some(picker) = self.picker.as_mut() {
+            picker.step(step);
+        }
+        self.draw_history()
+    }
+
+    /// Closes the picker and jumps to the selected entry
+    pub fn picker_choose(&mut self) -> io::Result<()> {
+        let Some(picker) = self.picker.take() else { return Ok(()) };
+        match picker.current() {
+            Some(Item { buffer, entry: Some(entry), .. }) => self.jump_to_entry(buffer.clone(), *entry),
+            // Only in the local history: open the conversation when we can tell what it is
+            Some(Item { buffer, entry: None, .. }) if self.buffers.contains_key(buffer) || get_user_by_id(buffer).is_some() => self.switch_to(buffer.clone()),
+            Some(Item { buffer, .. }) => {
+                let name = self.history.conversations.get(buffer).map_or_else(|| short_id(buffer), |log| log.name.clone());
+                self.draw_history()?;
+                self.notice(format!("{} is only in the local history, meow-cli export --user {} --format txt prints it", name, buffer))
+            }
+            None => self.draw_history(),
+        }
+    }
+
+    /// Switches to `buffer` and scrolls so that entry `entry` is at the top of the pane
+    pub fn jump_to_entry(&mut self, buffer: String, entry: usize) -> io::Result<()> {
+        if buffer != self.active {
+            self.switch_to(buffer)?;
+        }
+
+        let height = self.input_line.saturating_sub(2) as usize;
+        let laid = self.history_rows();
+        if let Some(start) = laid.starts.get(entry) {
+            self.buffers.get_mut(&self.active).unwrap().scroll = laid.rows.len().saturating_sub(start + height);
+        }
+        self.draw_history()
+    }
+
+    /// Lists every message that mentions us, across all open conversations
+    pub fn show_mentions(&mut self) -> io::Result<()> {
+        let mut items = Vec::new();
+        for id in &self.order {
+            let buffer = &self.buffers[id];
+            for (entry, chat) in buffer.entries.iter().enumerate().filter_map(|(index, entry)| match entry {
+                Entry::Chat(chat, ..) => Some((index, chat)),
+                _ => None,
+            }) {
+                if chat.user_id != self.me.id && mentions(&chat.chat, &self.me.name) {
+                    let text = format!("[{}] {}: {}", buffer.name, chat.user_name, chat.chat.replace('\n', " "));
+                    items.push(Item { buffer: id.clone(), entry: Some(entry), text });
+                }
+            }
+        }
+        self.open_picker(Picker::new(format!("Mentions of @{}", self.me.name), items))
+    }
+
+    /// Searches the chats of every open conversation and lists the matches in a picker.
+    /// A conversation named with in: is fetched from the server first, opening it if needed.
+    pub fn search(&mut self, arguments: &str) -> io::Result<()> {
+        let query = match Query::parse(arguments) {
+            Ok(query) => query,
+            Err(error) => return self.error(error),
+        };
+
+        // The conversation is searched once the server has sent its log
+        if let Some(conversation) = &query.conversation {
+            let id = self.resolve(conversation);
+            let (room, me) = (self.buffers.get(&id).is_some_and(|buffer| buffer.room), self.me.id.clone());
+            self.run(move |client| {
+                let result = match &id {
+                    _ if room => client.get_room(&id).map(|room| (room.chat_log, false)),
+                    id if *id == me => client.get_chats(id).map(|chat_log| (chat_log, false)),
+                    id => client.get_conversation(&me, id),
+                };
+                Done::Fetched { result, id, query }
+            });
+            return Ok(());
+        }
+        // Every open conversation, then those only the local history still has
+        let mut kept: Vec<(&String, &String)> =
+            self.history.conversations.iter().filter(|(id, _)| !self.buffers.contains_key(*id)).map(|(id, log)| (&log.name, id)).collect();
+        kept.sort();
+        let ids: Vec<String> = self.order.iter().chain(kept.into_iter().map(|(_, id)| id)).cloned().collect();
+        self.list_matches(&query, &ids)
+    }
+
+    // Lists the chats in the conversations with `ids` that match a search in a picker: what the
+    // buffers show, and what only the local history has, like chats from before a server restart
+    fn list_matches(&mut self, query: &Query, ids: &[String]) -> io::Result<()> {
+        let mut items = Vec::new();
+        for id in ids {
+            let buffer = self.buffers.get(id);
+            for (entry, item) in buffer.iter().flat_map(|buffer| buffer.entries.iter().enumerate()) {
+                let Entry::Chat(chat, ..) = item else { continue };
+                if let Some(found) = query.find(chat) {
+                    let text = format!("[{}] {}: {}", buffer.unwrap().name, chat.user_name, snippet(&chat.chat, found));
+                    items.push(Item { buffer: id.clone(), entry: Some(entry), text });
+                }
+            }
+
+            // The server's copy wins, the history only adds the chats it does not have
+            let Some(log) = self.history.conversations.get(id) else { continue };
+            let name = buffer.map_or(&log.name, |buffer| &buffer.name);
+            let server = buffer.map_or(&[][..], |buffer| buffer.raw.as_slice());
+            for chat in self.history.unsent(id, server).iter().filter_map(|chat| self.read_kept(chat)) {
+                if let Some(found) = query.find(&chat) {
+                    let text = format!("[{}] {}: {} (kept locally)", name, chat.user_name, snippet(&chat.chat, found));
+                    items.push(Item { buffer: id.clone(), entry: None, text });
+                }
+            }
+        }
+
+        let mut title = format!("Search '{}'", query.text);
+        if let Some(sender) = &query.sender {
+            title.push_str(&format!(" from {}", sender));
+        }
+        if let Some(conversation) = &query.conversation {
+            title.push_str(&format!(" in {}", conversation));
+        }
+        self.open_picker(Picker::new(title, items))
+    }
+
+    // Finds the conversation id for a name or id, trying open buffers, then the user directory,
+    // then the conversations in the local history
+    fn resolve(&self, name_or_id: &str) -> String {
+        let open = self.order.iter().find(|id| **id == name_or_id || self.buffers[*id].name.eq_ignore_ascii_case(name_or_id));
+        let kept = || {
+            let mut conversations = self.history.conversations.iter();
+            conversations.find(|(id, log)| **id == name_or_id || log.name.eq_ignore_ascii_case(name_or_id)).map(|(id, _)| id.clone())
+        };
+        match open {
+            Some(id) => id.clone(),
+            None => find_user(name_or_id).map(|user| user.id).or_else(kept).unwrap_or_else(|| name_or_id.to_string()),
+        }
+    }
+
+    // A chat from the local history as it reads, opened when it is sealed to us. Its signature is
+    // not checked, and key announcements are not chats.
+    fn read_kept(&self, chat: &Chat) -> Option<Chat> {
+        let body = signing::split(&chat.chat).0;
+        if e2e::announced_keys(body).is_some() {
+            return None;
+        }
+        match e2e::open(self.identity.as_ref(), &self.me.id, body) {
+            Opened::Plain => Some(Chat { chat: body.to_string(), ..chat.clone() }),
+            Opened::Sealed { chat, .. } => Some(chat),
+            Opened::Unreadable(_) => None,
+        }
+    }
+
+    // Searches a chat log pulled from the server rather than waiting for the sync task
+    fn search_fetched(&mut self, id: String, query: Query, result: Result<(Vec<Chat>, bool), ApiError>) -> io::Result<()> {
+        match result {
+            Ok((chat_log, true)) => self.apply_thread(id.clone(), chat_log)?,
+            Ok((chat_log, false)) => {
+                self.open_buffer(&id)?;
+                let name = self.buffers[&id].name.clone();
+                self.apply_log(User { name, id: id.clone(), chat_log, keys: None })?;
+            }
+            Err(error) => {
+                // What is here and in the local history can still be searched
+                let kept = self.buffers.contains_key(&id) || self.history.conversations.contains_key(&id);
+                let problem = match error {
+                    ApiError::NotFound => format!("The server has no user with id {}", id),
+                    error => format!("Could not fetch {}: {}", short_id(&id), error),
+                };
+                self.error(format!("{}{}", problem, if kept { ", searching what is kept here" } else { "" }))?;
+                if !kept {
+                    return Ok(());
+                }
+            }
+        }
+        self.list_matches(&query, &[id])
+    }
+
+    /// Writes the active conversation to a file, `/export json|md|txt|html [file]`
+    pub fn export(&mut self, arguments: &str) -> io::Result<()> {
+        let (format, path) = arguments.split_once(' ').unwrap_or((arguments, ""));
+        let Some(format) = Format::parse(format) else {
+            return self.error(format!("Usage: /export {} [file]", FORMATS));
+        };
+
+        let (name, chats) = match self.history.conversations.get(&self.active) {
+            Some(log) => (log.name.clone(), log.chats.as_slice()),
+            None => (self.buffers[&self.active].name.clone(), &[][..]),
+        };
+        let count = chats.len();
+        let text = export::render(format, &self.active, &name, chats, self.identity.as_ref(), &self.me.id);
+
+        // Without a file name the export lands in the working directory, named after the conversation
+        let path = match path.trim() {
+            "" => {
+                let name: String = name.chars().map(|c| if c.is_alphanumeric() { c } else { '-' }).collect();
+                format!("meow-{}.{}", name, format.extension())
+            }
+            path => path.to_string(),
+        };
+        match fs::write(&path, text) {
+            Ok(()) => self.notice(format!("Exported {} chats to {}", count, path)),
+            Err(error) => self.error(format!("Could not write {}: {}", path, error)),
+        }
+    }
+
+    /// Puts the chats from the local history back on the server, signing their users up again where needed
+    pub fn replay(&mut self) -> io::Result<()> {
+        let history = self.history.clone();
+        self.run(move |client| {
+            let mut restored = 0;
+            for (id, _) in history.conversations.iter().filter(|(_, log)| !log.shared) {
+                match replay_conversation(client, &history, id) {
+                    Ok(count) => restored += count,
+                    Err(error) => {
+                        return Done::Replayed(Err(format!("Replay stopped at {}: {}, {} chats restored so far", short_id(id), error, restored)));
+                    }
+                }
+            }
+            Done::Replayed(Ok(restored))
+        });
+        self.notice("Replaying the local history".to_string())
+    }
+
+    /// Whether the input line is taken up by a prompt or a question
+    pub fn asking(&self) -> bool {
+        !matches!(self.mode, Mode::Compose)
+    }
+
+    /// Asks for a line on the input row, showing stars instead of the text when `hidden`.
+    /// The answer goes to `then` once Enter is pressed, or Esc gives up.
+    pub fn ask(&mut self, label: &str, hidden: bool, then: Asked) -> io::Result<()> {
+        self.mode = Mode::Ask { label: label.to_string(), hidden, answer: String::new(), then };
+        self.draw_input()
+    }
+
+    /// Asks a yes/no question below the input line, only y accepts
+    pub fn confirm(&mut self, question: String, then: Confirmed) -> io::Result<()> {
+        self.mode = Mode::Confirm { question, then };
+        self.draw_input()
+    }
+
+    /// Takes a key or paste while a prompt or question is up
+    pub fn answer(&mut self, event: Event) -> io::Result<()> {
+        match &mut self.mode {
+            Mode::Compose => return Ok(()),
+            Mode::Ask { answer, then, .. } => match event {
+                Event::Key(KeyEvent { code: KeyCode::Enter, .. }) => return self.answered(true),
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => return self.answered(false),
+                Event::Key(KeyEvent { code: KeyCode::Backspace, .. }) => {
+                    answer.pop();
+                }
+                Event::Key(KeyEvent { code: KeyCode::Char(c), .. }) => answer.push(c),
+                // IDs are a single token, so whitespace goes
+                Event::Paste(text) if matches!(then, Asked::Switch) => answer.extend(text.chars().filter(|c| !c.is_whitespace())),
+                // A single line, so pasted line breaks become spaces
+                Event::Paste(text) => answer.extend(text.chars().map(|c| if c.is_control() { ' ' } else { c })),
+                _ => {}
+            },
+            Mode::Confirm { .. } => {
+                if let Event::Key(KeyEvent { code, .. }) = event {
+                    match code {
+                        KeyCode::Char('y') | KeyCode::Char('Y') => return self.answered(true),
+                        KeyCode::Char(_) | KeyCode::Enter | KeyCode::Esc => return self.answered(false),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.draw_input()
+    }
+
+    // Puts the composer back and goes ahead with whatever the prompt or question was for
+    fn answered(&mut self, yes: bool) -> io::Result<()> {
+        let mode = std::mem::replace(&mut self.mode, Mode::Compose);
+        if let Mode::Confirm { .. } = mode {
+            // Clear the question once answered
+            execute!(self.stdout, cursor::MoveTo(0, self.input_line + 1), Clear(ClearType::CurrentLine))?;
+        }
+        self.draw_input()?;
+
+        match mode {
+            Mode::Compose => Ok(()),
+            Mode::Ask { then: Asked::Switch, answer, .. } if yes && !answer.trim().is_empty() => self.switch_to(answer.trim().to_string()),
+            Mode::Ask { then: Asked::Search, answer, .. } if yes => self.search(&answer),
+            Mode::Ask { then: Asked::Secret(name), answer, .. } if yes => {
+                self.run(move |client| Done::LoggedIn { result: client.login(&name, &answer), name });
+                Ok(())
+            }
+            Mode::Ask { then: Asked::Secret(_), .. } => self.notice("Not logged in, type /login to try again".to_string()),
+            Mode::Ask { .. } => Ok(()),
+            Mode::Confirm { then: Confirmed::Paste(text), .. } => {
+                if yes {
+                    self.message.push_str(&text);
+                }
+                self.draw_input()
+            }
+        }
+    }
+
+    /// Completes the @name at the end of the composer against the known users
+    pub fn complete_mention(&mut self) -> io::Result<()> {
+        mention::complete(&mut self.message, &user_names(), &mut self.completion);
+        self.draw_input()
+    }
+
+    /// Scrolls the history pane by `rows`, positive values go back in time
+    pub fn scroll(&mut self, rows: isize) -> io::Result<()> {
+        let height = self.input_line.saturating_sub(2) as usize;
+        let total = self.history_rows().rows.len();
+
+        let buffer = self.buffers.get_mut(&self.active).unwrap();
+        buffer.scroll = buffer.scroll.saturating_add_signed(rows).min(total.saturating_sub(height));
+        self.draw_history()
+    }
+
+    /// Jumps to the first unread message, in this buffer if it has one, else in the first buffer that does
+    pub fn jump_to_unread(&mut self) -> io::Result<()> {
+        if self.buffers[&self.active].divider.is_none() {
+            let next = self.order.iter().find(|id| self.buffers[*id].unread() > 0).cloned();
+            match next {
+                Some(id) => self.switch_to(id)?,
+                None => return self.notice("No unread messages".to_string()),
+            }
+        }
+
+        // Scroll so the divider sits at the top of the pane
+        let height = self.input_line.saturating_sub(2) as usize;
+        let laid = self.history_rows();
+        if let Some(divider) = laid.divider {
+            self.buffers.get_mut(&self.active).unwrap().scroll = laid.rows.len().saturating_sub(divider + height);
+        }
+        self.draw_history()
+    }
+
+    // Remembers how far a conversation has been read for the next run
+    fn save_read(&mut self, id: &str) -> io::Result<()> {
+        let read = self.buffers[id].read;
+        if self.store.read.insert(id.to_string(), read) == Some(read) {
+            return Ok(());
+        }
+        self.save_store()
+    }
+
+    fn save_history(&mut self) -> io::Result<()> {
+        match self.history.save() {
+            Ok(()) => Ok(()),
+            Err(error) => self.error(format!("Could not save the chat history: {}", error)),
+        }
+    }
+
+    fn save_store(&mut self) -> io::Result<()> {
+        match self.store.save() {
+            Ok(()) => Ok(()),
+            Err(error) => self.error(format!("Could not save local state: {}", error)),
+        }
+    }
+
+    /// Redraws the input line: the composer, showing pasted newlines as a return symbol, or the
+    /// prompt or question that has taken its place
+    pub fn draw_input(&mut self) -> io::Result<()> {
+        let (label, shown) = match &self.mode {
+            Mode::Ask { label, hidden: true, answer, .. } => (label.as_str(), "*".repeat(answer.chars().count())),
+            Mode::Ask { label, answer, .. } => (label.as_str(), answer.clone()),
+            _ => ("", self.message.replace('\n', "\u{21b5}")),
+        };
+        let width = (self.cols.saturating_sub(3) as usize).saturating_sub(display_width(label));
+
+        // Only show the tail of the message if it does not fit
+        let skip = shown.chars().count().saturating_sub(width);
+        let visible: String = shown.chars().skip(skip).collect();
+
+        execute!(self.stdout, cursor::MoveTo(1, self.input_line), Clear(ClearType::CurrentLine), Print(label), Print(visible))?;
+        if let Mode::Confirm { question, .. } = &self.mode {
+            execute!(self.stdout, cursor::MoveTo(0, self.input_line + 1), Clear(ClearType::CurrentLine), Print(format!("{} [y/N] ", question)))?;
+        }
+        Ok(())
+    }
+
+    /// Logs in to the server as `name`, or the name we logged in with last, asking for the secret
+    pub fn login(&mut self, name: &str) -> io::Result<()> {
+        let name = match name {
+            "" => self.net.client.login_name().unwrap_or_else(|| self.me.name.clone()),
+            name => name.to_string(),
+        };
+        self.ask(&format!("Secret for {}: ", name), true, Asked::Secret(name))
+    }
+
+    // Hands a call to the network task and shows that something is on its way
+    fn run(&mut self, job: impl FnOnce(&Client) -> Done + Send + 'static) {
+        self.status.busy += 1;
+        self.net.run(job);
+    }
+
+    /// Takes in how a call the network task made went
+    pub fn finish(&mut self, done: Done) -> io::Result<()> {
+        self.status.busy = self.status.busy.saturating_sub(1);
+        match done {
+            Done::Sent { result: Ok(()), .. } => {}
+            Done::Sent { to, entry, posted, result: Err(error) } => {
+                self.pending.retain(|pending| *pending != (to.clone(), posted.clone(), entry));
+                // Our copy went on screen right away, it turns into the reason it never arrived
+                if let Some(buffer) = self.buffers.get_mut(&to) {
+                    if let Some(Entry::Chat(chat, ..)) = buffer.entries.get(entry) {
+                        buffer.entries[entry] = Entry::Error(format!("Message not sent: {}: {}", error, chat.chat));
+                    }
+                }
+                if to == self.active {
+                    self.paint_history()?;
+                }
+            }
+            Done::Published { result: Ok(true), .. } => {}
+            // Nothing we send replaces them, so there is no point trying again
+            Done::Published { result: Ok(false), .. } => {
+                self.error("Our profile holds keys that are not ours, someone published them under our id first".to_string())?;
+            }
+            Done::Published { keys, result: Err(error) } => {
+                // Try again on the next sync round
+                if self.announced.get(&self.me.id) == Some(&keys) {
+                    self.announced.remove(&self.me.id);
+                }
+                self.error(format!("Could not publish our keys: {}", error))?;
+            }
+            Done::Fetched { id, query, result } => self.search_fetched(id, query, result)?,
+            Done::LoggedIn { name, result: Ok(()) } => self.notice(format!("Logged in as {}", name))?,
+            Done::LoggedIn { name, result: Err(error) } => self.error(format!("Could not log in as {}: {}", name, error))?,
+            Done::LookedUp { id, result: Ok(user) } => self.found_keys(&id, user)?,
+            Done::LookedUp { .. } => {}
+            Done::Replayed(Ok(restored)) => self.notice(format!("Restored {} chats", restored))?,
+            Done::Replayed(Err(error)) => self.error(error)?,
+            Done::Rooms { listed, result: Ok(rooms) } => self.found_rooms(listed, rooms)?,
+            // The Go server has no rooms, which only matters when asked for them
+            Done::Rooms { listed: false, .. } => {}
+            Done::Rooms { result: Err(error), .. } => self.error(format!("Could not list the rooms: {}", error.about_room()))?,
+            Done::Joined { result: Ok(room), .. } => {
+                let (id, name) = (room.id.clone(), room.name.clone());
+                self.open_room(&room);
+                self.switch_to(id)?;
+                self.apply_room(room)?;
+                self.notice(format!("Joined #{}", name))?;
+            }
+            Done::Joined { name, result: Err(error) } => self.error(format!("Could not join #{}: {}", name, error.about_room()))?,
+            Done::Left { result: Ok(room), .. } => self.close_room(&room)?,
+            Done::Left { name, result: Err(error) } => self.error(format!("Could not leave #{}: {}", name, error.about_room()))?,
+        }
+        self.draw_status()
+    }
+
+    /// Keeps the busy spinner turning while calls are out
+    pub fn tick(&mut self) -> io::Result<()> {
+        if self.status.busy == 0 {
+            return Ok(());
+        }
+        self.status.frame += 1;
+        self.draw_status()
+    }
+
+    /// Lists the rooms on the server, marking the ones we are in
+    pub fn list_rooms(&mut self) -> io::Result<()> {
+        self.run(|client| Done::Rooms { listed: true, result: client.get_rooms() });
+        Ok(())
+    }
+
+    /// Makes a room named `name` and joins it
+    pub fn create_room(&mut self, name: &str) -> io::Result<()> {
+        let name = name.trim_start_matches('#').to_string();
+        if name.is_empty() {
+            return self.error("Usage: /create <room>".to_string());
+        }
+        let me = self.me.id.clone();
+        self.run(move |client| Done::Joined { result: client.create_room(&name, &me), name });
+        Ok(())
+    }
+
+    /// Joins the room called `name` and opens its buffer
+    pub fn join_room(&mut self, name: &str) -> io::Result<()> {
+        let name = name.trim_start_matches('#').to_string();
+        if name.is_empty() {
+            return self.error("Usage: /join <room>".to_string());
+        }
+        let me = self.me.id.clone();
+        self.run(move |client| Done::Joined { result: client.join_room(&name, &me), name });
+        Ok(())
+    }
+
+    /// Leaves the room called `name`, or the room on screen when no name is given
+    pub fn leave_room(&mut self, name: &str) -> io::Result<()> {
+        let name = match name.trim_start_matches('#') {
+            "" if self.buffers[&self.active].room => self.active.clone(),
+            "" => return self.error("Usage: /leave <room>, or /leave in the room".to_string()),
+            name => name.to_string(),
+        };
+        let me = self.me.id.clone();
+        // The room id makes a poor name in messages, show the buffer's instead
+        let shown = self.buffers.get(&name).map_or_else(|| name.clone(), |buffer| buffer.name.trim_start_matches('#').to_string());
+        self.run(move |client| Done::Left { result: client.leave_room(&name, &me), name: shown });
+        Ok(())
+    }
+
+    // Opens buffers for the rooms we are in, and lists them all when asked to
+    fn found_rooms(&mut self, listed: bool, rooms: Vec<Room>) -> io::Result<()> {
+        let me = self.me.id.clone();
+        for room in rooms.iter().filter(|room| room.members.contains(&me)) {
+            self.open_room(room);
+        }
+        self.paint_frame()?;
+        if !listed {
+            return self.draw_input();
+        }
+        if rooms.is_empty() {
+            return self.notice("There are no rooms yet, /create one".to_string());
+        }
+        for room in &rooms {
+            let joined = if room.members.contains(&self.me.id) { ", joined" } else { "" };
+            self.notice(format!("#{}: {} members{}", room.name, room.members.len(), joined))?;
+        }
+        Ok(())
+    }
+
+    // Adds a buffer for a room if there is none yet, and starts syncing it
+    fn open_room(&mut self, room: &Room) {
+        if !self.buffers.contains_key(&room.id) {
+            let mut buffer = Buffer::new(format!("#{}", room.name));
+            buffer.room = true;
+            self.buffers.insert(room.id.clone(), buffer);
+            self.order.push(room.id.clone());
+        }
+        let _ = self.sync.send(SyncCommand::WatchRoom(room.id.clone()));
+    }
+
+    // Drops the buffer of a room we left, moving to our inbox if it was on screen
+    fn close_room(&mut self, room: &Room) -> io::Result<()> {
+        let _ = self.sync.send(SyncCommand::UnwatchRoom(room.id.clone()));
+        if self.active == room.id {
+            self.switch_to(self.me.id.clone())?;
+        }
+        self.buffers.remove(&room.id);
+        self.order.retain(|id| *id != room.id);
+        self.pending.retain(|(id, ..)| *id != room.id);
+        self.paint_frame()?;
+        self.notice(format!("Left #{}", room.name))
+    }
+
+    // Merges the direct messages with `peer` into their buffer, opening it when they wrote first
+    fn apply_thread(&mut self, peer: String, chat_log: Vec<Chat>) -> io::Result<()> {
+        if !self.buffers.contains_key(&peer) {
+            self.open_buffer(&peer)?;
+            // Someone we had no conversation with wrote to us, all of it is news
+            self.buffers.get_mut(&peer).unwrap().loaded = true;
+        }
+        self.buffers.get_mut(&peer).unwrap().thread = true;
+        let name = self.name_of(&peer);
+        self.apply_log(User { name, id: peer, chat_log, keys: None })
+    }
+
+    // Merges a room's chat log into its buffer, the same way as a user's
+    fn apply_room(&mut self, room: Room) -> io::Result<()> {
+        self.apply_log(User { name: format!("#{}", room.name), id: room.id, chat_log: room.chat_log, keys: None })
+    }
+
+    /// Adds a notice to the active buffer
+    pub fn notice(&mut self, text: String) -> io::Result<()> {
+        self.push_entry(Entry::Notice(text))
+    }
+
+    /// Adds an error to the active buffer
+    pub fn error(&mut self, text: String) -> io::Result<()> {
+        self.push_entry(Entry::Error(text))
+    }
+
+    fn push_entry(&mut self, entry: Entry) -> io::Result<()> {
+        let buffer = self.buffers.get_mut(&self.active).unwrap();
+        buffer.entries.push(entry);
+        buffer.scroll = 0; // Anything new brings the pane back to the bottom
+        self.draw_history()
+    }
+
+    /// Switches the history pane to the conversation with `id` and starts syncing it
+    pub fn switch_to(&mut self, id: String) -> io::Result<()> {
+        // The divider only marks what was new while we were away, so drop it when leaving
+        let left = self.buffers.get_mut(&self.active).unwrap();
+        left.divider = None;
+        left.scroll = 0;
+
+        self.open_buffer(&id)?;
+        self.buffers.get_mut(&id).unwrap().mark_read(true);
+        self.save_read(&id)?;
+
+        self.active = id;
+        self.draw()
+    }
+
+    // Adds a buffer for the conversation with `id` if there is none yet, and starts syncing it
+    fn open_buffer(&mut self, id: &str) -> io::Result<()> {
+        if !self.buffers.contains_key(id) {
+            let name = get_user_by_id(id).map_or_else(|| short_id(id), |user| user.name);
+            self.buffers.insert(id.to_string(), Buffer::new(name));
+            self.order.push(id.to_string());
+            if id != self.me.id {
+                self.store.open.push(id.to_string());
+                self.save_store()?;
+            }
+        }
+
+        // Errors here mean the sync task is gone, the status line already shows why
+        let watch = if self.buffers[id].room { SyncCommand::WatchRoom(id.to_string()) } else { SyncCommand::Watch(id.to_string()) };
+        let _ = self.sync.send(watch);
+        Ok(())
+    }
+
+    /// Moves to the next (or with `back`, previous) buffer in the buffer list
+    pub fn cycle(&mut self, back: bool) -> io::Result<()> {
+        let position = self.order.iter().position(|id| *id == self.active).unwrap_or(0);
+        let next = if back { position + self.order.len() - 1 } else { position + 1 } % self.order.len();
+        self.switch_to(self.order[next].clone())
+    }
+
+    /// Posts the composer contents to the active conversation
+    pub fn send(&mut self, text: String) -> io::Result<()> {
+        // Sealed conversations post an envelope, while our copy on screen keeps the text
+        let (posted, lock) = if self.store.encrypted.contains(&self.active) {
+            match self.seal(&text) {
+                Ok(sealed) => (sealed, Lock::Sealed),
+                Err(error) => return self.error(format!("Message not sent: {}", error)),
+            }
+        } else {
+            (text.clone(), Lock::Plain)
+        };
+        // Everything we post is signed, so others can tell it really came from us
+        let posted = match &self.identity {
+            Some(identity) => signing::sign(identity, &self.me.id, &self.active, &posted),
+            None => posted,
+        };
+        let signature = if self.identity.is_some() { Signature::Verified } else { Signature::Unverified };
+        let chat = Chat { chat: posted, user_id: self.me.id.clone(), user_name: self.me.name.clone() };
+
+        // Show our copy right away, the server's copy is skipped when it syncs back
+        let to = self.active.clone();
+        let entry = self.buffers[&to].entries.len();
+        self.pending.push((to.clone(), chat.chat.clone(), entry));
+        self.push_entry(Entry::Chat(Chat { chat: text, user_id: self.me.id.clone(), user_name: self.me.name.clone() }, lock, signature))?;
+        let (room, me) = (self.buffers[&to].room, self.me.id.clone());
+        self.run(move |client| {
+            let result = match &to {
+                _ if room => client.send_room_chat(&to, &chat),
+                // Notes to ourselves go to our own chat log, like everything on a server without DM threads
+                to if *to == me => client.send_chat(to, &chat),
+                to => client.send_to(&me, to, &chat),
+            };
+            Done::Sent { result: result.map(|_| ()), to, entry, posted: chat.chat }
+        });
+        self.draw_status()
+    }
+
+    // Raises one notification for a batch of new messages, if the settings allow any of them
+    fn notify(&mut self, id: &str, name: &str, fresh: &[Chat]) -> io::Result<()> {
+        let settings = &self.store.notify;
+        let wanted: Vec<&Chat> = fresh
+            .iter()
+            .filter(|chat| settings.allows(id, mentions(&chat.chat, &self.me.name)))
+            .collect();
+
+        let body = match wanted.as_slice() {
+            [] => return Ok(()),
+            [chat] => format!("{}: {}", chat.user_name, chat.chat),
+            many => format!("{} new messages", many.len()),
+        };
+        self.notifier.notify(&mut self.stdout, &format!("meow - {}", name), &body)
+    }
+
+    // Seals a message for the peer of the active conversation and for ourselves, so our copy opens too
+    fn seal(&self, text: &str) -> Result<String, String> {
+        let identity = self.identity.as_ref().ok_or("there is no key pair to seal with")?;
+        let mut recipients = vec![(self.me.id.clone(), identity.public_key())];
+
+        if self.active != self.me.id {
+            let name = &self.buffers[&self.active].name;
+            let key = self.store.pins.get(&self.active).ok_or_else(|| format!("{} has not published an encryption key yet", name))?;
+            if self.announced.get(&self.active).is_some_and(|announced| announced.seal != *key) {
+                return Err(format!("{} published a new key, check with them and /trust it first", name));
+            }
+            recipients.push((self.active.clone(), key.clone()));
+        }
+        e2e::seal(identity, &self.me.id, &self.me.name, text, &recipients)
+    }
+
+    // Publishes our public keys in our profile unless they are out there already
+    fn publish_keys(&mut self) -> io::Result<()> {
+        let Some(identity) = &self.identity else { return Ok(()) };
+        let published = identity.published();
+        if self.announced.get(&self.me.id) == Some(&published) {
+            return Ok(());
+        }
+
+        let me = User { name: self.me.name.clone(), id: self.me.id.clone(), chat_log: Vec::new(), keys: Some(identity.announcement(&self.me.id)) };
+        // Counted as published right away so the next sync round does not post it again
+        self.announced.insert(self.me.id.clone(), published.clone());
+        self.run(move |client| Done::Published { result: publish(client, me), keys: published });
+        Ok(())
+    }
+
+    /// Turns end-to-end encryption on or off for the active conversation
+    pub fn set_encrypted(&mut self, on: bool) -> io::Result<()> {
+        if on && self.identity.is_none() {
+            return self.error("There is no key pair, so messages can not be sealed".to_string());
+        }
+        if on && self.buffers[&self.active].room {
+            return self.error("Messages to rooms can not be sealed".to_string());
+        }
+
+        let id = self.active.clone();
+        self.store.encrypted.retain(|encrypted| *encrypted != id);
+        if on {
+            self.store.encrypted.push(id.clone());
+        }
+        self.save_store()?;
+        self.draw_status()?;
+
+        let name = self.buffers[&id].name.clone();
+        if !on {
+            return self.notice(format!("Messages to {} are sent in the clear", name));
+        }
+        let notice = match self.store.pins.get(&id) {
+            _ if id == self.me.id => "Notes to ourselves are sealed".to_string(),
+            Some(key) => format!("Messages to {} are sealed, their key is {}", name, key),
+            None => format!("Messages to {} will be sealed once they publish a key", name),
+        };
+        self.notice(notice)
+    }
+
+    /// Pins the keys the active conversation published last, after they changed
+    pub fn trust(&mut self) -> io::Result<()> {
+        let id = self.active.clone();
+        let name = self.buffers[&id].name.clone();
+        let Some(published) = self.announced.get(&id).cloned() else {
+            return self.error(format!("{} has not published any keys", name));
+        };
+        self.store.pins.insert(id.clone(), published.seal.clone());
+        if let Some(sign) = published.sign {
+            self.store.signers.insert(id, sign);
+        }
+        self.save_store()?;
+        self.notice(format!("Trusting {} with key {}", name, published.seal))
+    }
+
+    // Turns a chat from the server into what the history pane shows. Sealed chats are opened, signatures
+    // checked, and both against the pinned keys. Published keys are pinned the first time they are seen.
+    fn open_chat(&mut self, conversation: &str, chat: &Chat) -> Entry {
+        let (body, claim) = signing::split(&chat.chat);
+        // Keys count where their owner published them, not in a room someone pasted them into
+        let (room, thread) = self.buffers.get(conversation).map_or((false, false), |buffer| (buffer.room, buffer.thread));
+        if !room && e2e::announced_keys(body).is_some() {
+            // Keys kept in the profile are the ones that count, whatever else was posted to the log
+            let profile = self.profiles.get(conversation).and_then(|keys| signing::announced_by(conversation, keys));
+            return match signing::announced_by(conversation, &chat.chat) {
+                Some(published) if profile.as_ref().is_some_and(|profile| *profile != published) => {
+                    Entry::Error(format!("Ignored keys for {} that are not the ones in their profile", self.name_of(conversation)))
+                }
+                Some(published) => self.pin_keys(conversation, published),
+                None => Entry::Error(format!("Ignored keys for {} that are not signed with them", self.name_of(conversation))),
+            };
+        }
+
+        let (mut shown, lock) = match e2e::open(self.identity.as_ref(), &self.me.id, body) {
+            Opened::Plain => (Chat { chat: body.to_string(), ..chat.clone() }, Lock::Plain),
+            Opened::Sealed { chat, key } => {
+                let pinned = if chat.user_id == self.me.id {
+                    self.identity.as_ref().map(Identity::public_key)
+                } else {
+                    // Trust on first use: the first key seen for a sender is the one expected from then on
+                    Some(self.store.pins.entry(chat.user_id.clone()).or_insert_with(|| key.clone()).clone())
+                };
+                let lock = if pinned == Some(key) { Lock::Sealed } else { Lock::KeyChanged };
+                (chat, lock)
+            }
+            Opened::Unreadable(reason) => (Chat { chat: format!("sealed message, {}", reason), ..chat.clone() }, Lock::Unreadable),
+        };
+
+        let signature = match &claim {
+            None => Signature::Unverified,
+            // A sealed chat names its sender inside, a signature by anybody else does not belong to it
+            Some(claim) if matches!(lock, Lock::Sealed | Lock::KeyChanged) && claim.from != shown.user_id => Signature::Forged,
+            // One we can not open does not tell who sent it, so there is nothing to hold the signature against
+            Some(_) if lock == Lock::Unreadable => Signature::Unverified,
+            Some(claim) => {
+                if claim.from != self.me.id && !self.store.signers.contains_key(&claim.from) {
+                    self.look_up_keys(&claim.from);
+                }
+                let key = if claim.from == self.me.id {
+                    self.identity.as_ref().and_then(|identity| identity.published().sign)
+                } else {
+                    self.store.signers.get(&claim.from).cloned()
+                };
+                // Each side of a DM thread signs to the other, so the peer's chats were signed to us
+                let to = if thread && claim.from == conversation { self.me.id.as_str() } else { conversation };
+                key.map_or(Signature::Unverified, |key| signing::verify(&key, claim, to, body))
+            }
+        };
+
+        // The server files every chat under the conversation it was posted to, a good signature tells who really wrote it
+        if let (Some(claim), Signature::Verified, Lock::Plain) = (&claim, signature, lock) {
+            shown.user_name = self.name_of(&claim.from);
+            shown.user_id = claim.from.clone();
+        }
+        Entry::Chat(shown, lock, signature)
+    }
+
+    // Pins the keys published in a conversation the first time they are seen, warns when they change later
+    fn pin_keys(&mut self, conversation: &str, published: Published) -> Entry {
+        self.announced.insert(conversation.to_string(), published.clone());
+        if conversation == self.me.id {
+            return match &self.identity {
+                Some(identity) if identity.published() == published => Entry::Notice("Published our keys".to_string()),
+                _ => Entry::Error("Someone published keys that are not ours as ours".to_string()),
+            };
+        }
+
+        let name = self.name_of(conversation);
+        let seal = self.store.pins.entry(conversation.to_string()).or_insert_with(|| published.seal.clone());
+        let mut same = *seal == published.seal;
+        if let Some(sign) = published.sign {
+            same &= *self.store.signers.entry(conversation.to_string()).or_insert(sign.clone()) == sign;
+        }
+
+        if same {
+            Entry::Notice(format!("{} published their keys, pinned", name))
+        } else {
+            Entry::Error(format!("{} published different keys, /trust them if they really changed", name))
+        }
+    }
+
+    // Keys are published in their owner's profile or chat log, fetch it once for someone we have no key for yet
+    fn look_up_keys(&mut self, id: &str) {
+        if !self.looked_up.insert(id.to_string()) {
+            return;
+        }
+        let id = id.to_string();
+        self.run(move |client| Done::LookedUp { result: client.get_user(&id), id });
+    }
+
+    // Pins the keys found in a user's profile or chat log, then checks the signatures that were waiting on them
+    fn found_keys(&mut self, id: &str, user: User) -> io::Result<()> {
+        remember_user(&user);
+        let announced = signing::announcements(id, user.keys.as_deref(), &user.chat_log);
+        if announced.is_empty() {
+            return Ok(());
+        }
+        for published in announced {
+            // Later keys that differ are only flagged, one warning is enough
+            if let Entry::Error(error) = self.pin_keys(id, published) {
+                self.error(error)?;
+                break;
+            }
+        }
+
+        let mut changed = false;
+        for conversation in self.order.clone() {
+            let buffer = &self.buffers[&conversation];
+            let waiting: Vec<(usize, Chat)> = buffer
+                .chat_entries
+                .iter()
+                .zip(&buffer.raw)
+                .filter(|(entry, raw)| {
+                    matches!(buffer.entries[**entry], Entry::Chat(_, _, Signature::Unverified))
+                        && signing::split(&raw.chat).1.is_some_and(|claim| claim.from == id)
+                })
+                .map(|(entry, raw)| (*entry, raw.clone()))
+                .collect();
+            for (entry, raw) in waiting {
+                let opened = self.open_chat(&conversation, &raw);
+                self.buffers.get_mut(&conversation).unwrap().entries[entry] = opened;
+                changed |= conversation == self.active;
+            }
+        }
+        if changed { self.paint_history() } else { Ok(()) }
+    }
+
+    // The name to show for a user id, from the open buffers or the user directory
+    fn name_of(&self, id: &str) -> String {
+        if let Some(buffer) = self.buffers.get(id) {
+            return buffer.name.clone();
+        }
+        find_user(id).map_or_else(|| short_id(id), |user| user.name)
+    }
+
+    /// Mutes or unmutes notifications for the active conversation
+    pub fn set_muted(&mut self, muted: bool) -> io::Result<()> {
+        let id = self.active.clone();
+        self.store.notify.muted.retain(|muted_id| *muted_id != id);
+        if muted {
+            self.store.notify.muted.push(id);
+        }
+        self.save_store()?;
+
+        let name = self.buffers[&self.active].name.clone();
+        self.notice(format!("Notifications for {} {}", name, if muted { "muted" } else { "unmuted" }))
+    }
+
+    /// Picks which messages notify: all of them, only ones mentioning us, or none
+    pub fn set_notify_level(&mut self, level: Level) -> io::Result<()> {
+        self.store.notify.level = level;
+        self.save_store()?;
+        self.notice(match level {
+            Level::All => "Notifying for every message".to_string(),
+            Level::Mentions => format!("Notifying only when @{} is mentioned", self.me.name),
+            Level::Off => "Notifications off".to_string(),
+        })
+    }
+
+    /// Sets or clears the daily do-not-disturb window
+    pub fn set_quiet_hours(&mut self, quiet: Option<QuietHours>) -> io::Result<()> {
+        self.store.notify.quiet = quiet;
+        self.save_store()?;
+        self.notice(match quiet {
+            Some(quiet) => format!("Do not disturb between {}", quiet),
+            None => "Do not disturb off".to_string(),
+        })
+    }
+
+    /// Tracks terminal focus, coming back marks the buffer on screen as read
+    pub fn set_focus(&mut self, focused: bool) -> io::Result<()> {
+        self.focused = focused;
+        if !focused {
+            return Ok(());
+        }
+
+        let id = self.active.clone();
+        self.buffers.get_mut(&id).unwrap().mark_read(true);
+        self.save_read(&id)?;
+        self.draw()
+    }
+
+    /// Applies an update from the sync task
+    pub fn handle_sync(&mut self, event: SyncEvent) -> io::Result<()> {
+        match event {
+            SyncEvent::Connection(connection) => {
+                self.status.connection = connection;
+                self.draw_status()
+            }
+            SyncEvent::Users(users) => {
+                // Refresh the names of open conversations from the directory
+                for user in &users {
+                    if let Some(buffer) = self.buffers.get_mut(&user.id) {
+                        buffer.name = user.name.clone();
+                    }
+                }
+
+                set_users(&self.me, users);
+                self.paint_frame()?;
+                self.draw_status()
+            }
+            SyncEvent::User(user) => self.apply_log(user),
+            SyncEvent::Room(room) => self.apply_room(room),
+            SyncEvent::Thread { peer, chat_log } => self.apply_thread(peer, chat_log),
+            SyncEvent::Reregistered => {
+                // The server lost our published key along with everything else
+                self.announced.remove(&self.me.id);
+                self.notice(format!("The server lost its data, signed up again as {}", self.me.name))?;
+                match self.history.chat_count() {
+                    0 => Ok(()),
+                    count => self.notice(format!("Type /replay to put back the {} chats kept locally", count)),
+                }
+            }
+            // Ask right away rather than leaving the status bar stuck on "not logged in"
+            SyncEvent::LoginNeeded => {
+                self.notice("The server wants us to log in".to_string())?;
+                if self.asking() {
+                    return self.notice("Type /login once done here".to_string());
+                }
+                self.login("")
+            }
+            SyncEvent::Missing(id) => {
+                let Some(buffer) = self.buffers.get_mut(&id) else { return Ok(()) };
+                if buffer.missing {
+                    return Ok(());
+                }
+                buffer.missing = true;
+                let missing = if buffer.room { format!("The server has no room {}", buffer.name) } else { format!("The server has no user with id {}", id) };
+                buffer.entries.push(Entry::Error(missing));
+
+                if id == self.active { self.draw_history() } else { Ok(()) }
+            }
+        }
+    }
+
+    // Merges a user's chat log from the server into its buffer, or a room's passed off as one
+    fn apply_log(&mut self, user: User) -> io::Result<()> {
+        let active = user.id == self.active;
+        let Some(buffer) = self.buffers.get_mut(&user.id) else { return Ok(()) };
+        buffer.name = user.name.clone();
+        buffer.missing = false;
+
+        // The log shrinks when the in-memory Go server restarts, start counting again
+        // and keep the old chats around in case they get replayed
+        if user.chat_log.len() < buffer.synced {
+            buffer.synced = 0;
+            buffer.read = 0;
+            buffer.orphans = std::mem::take(&mut buffer.chat_entries);
+        }
+
+        // Open sealed chats and pin published keys before anything goes into the buffer, the keys
+        // in the profile before any announced in the log
+        let start = buffer.synced;
+        let pins = self.store.pins.len();
+        let profile = match user.keys.clone().filter(|keys| self.profiles.get(&user.id) != Some(keys)) {
+            Some(keys) => {
+                self.profiles.insert(user.id.clone(), keys.clone());
+                match signing::announced_by(&user.id, &keys) {
+                    Some(published) => Some(self.pin_keys(&user.id, published)),
+                    None => Some(Entry::Error(format!("Ignored keys in the profile of {} that are not signed with them", user.name))),
+                }
+            }
+            None => None,
+        };
+        let opened: Vec<Entry> = user.chat_log[start..].iter().map(|chat| self.open_chat(&user.id, chat)).collect();
+        if self.store.pins.len() != pins {
+            self.save_store()?;
+        }
+
+        let buffer = self.buffers.get_mut(&user.id).unwrap();
+        let noted = profile.is_some();
+        buffer.entries.extend(profile);
+        let mut fresh = Vec::new();
+        for ((index, chat), entry) in user.chat_log.iter().enumerate().skip(start).zip(opened) {
+            let echo = self.pending.iter().position(|(id, text, _)| *id == user.id && *text == chat.chat);
+            // Only the text is compared, our own copies of what we sent carry our id rather than the server's
+            let orphan = buffer.orphans.iter().position(|old| {
+                matches!((&buffer.entries[*old], &entry), (Entry::Chat(old, ..), Entry::Chat(new, ..)) if old.chat == new.chat)
+            });
+            match (echo, orphan) {
+                (Some(echo), _) => buffer.chat_entries.push(self.pending.remove(echo).2),
+                // A replayed chat takes the place of its old copy, and stays read if everything before it was
+                (None, Some(orphan)) => {
+                    buffer.chat_entries.push(buffer.orphans[orphan]);
+                    buffer.orphans.drain(..=orphan);
+                    if buffer.read == index {
+                        buffer.read += 1;
+                    }
+                }
+                (None, None) => {
+                    buffer.orphans.clear();
+                    buffer.chat_entries.push(buffer.entries.len());
+                    if let Entry::Chat(chat, ..) = &entry {
+                        fresh.push(chat.clone());
+                    }
+                    buffer.entries.push(entry);
+                }
+            }
+        }
+        buffer.synced = user.chat_log.len();
+        buffer.raw = user.chat_log.clone();
+
+        // Only messages arriving while we run are worth a notification, not the backlog from last time
+        let was_loaded = buffer.loaded;
+        let name = buffer.name.clone();
+        let shared = buffer.room || buffer.thread;
+
+        // Pick up where the last run left off, a conversation we never read starts out read
+        if !buffer.loaded {
+            buffer.read = self.store.read.get(&user.id).copied().unwrap_or(buffer.synced).min(buffer.synced);
+            buffer.loaded = true;
+        }
+        buffer.read = buffer.read.min(buffer.synced);
+
+        // Whatever lands in the buffer on screen is read straight away, unless we are looking elsewhere
+        if active && self.focused {
+            buffer.mark_read(!was_loaded);
+            self.save_read(&user.id)?;
+        }
+        if active && (noted || !fresh.is_empty()) {
+            self.paint_history()?;
+        }
+        if was_loaded && (!active || !self.focused) {
+            self.notify(&user.id, &name, &fresh)?;
+        }
+
+        // Keep a local copy, the Go server forgets everything when it restarts
+        if self.history.record(&user.id, &name, &user.chat_log) | (shared && self.history.mark_shared(&user.id)) {
+            self.save_history()?;
+        }
+
+        // Make sure our public keys are out there for others to seal with and check signatures against
+        if user.id == self.me.id {
+            self.publish_keys()?;
+        }
+        self.paint_frame()?;
+        self.draw_status()
+    }
+}
+
+// Sends the chats of one conversation that the server does not have, returns how many
+fn replay_conversation(client: &Client, history: &History, id: &str) -> Result<usize, ApiError> {
+    let log = &history.conversations[id];
+    match client.get_chats(id) {
+        Ok(server) => {
+            let unsent = history.unsent(id, &server);
+            for chat in &unsent {
+                client.send_chat(id, chat)?;
+            }
+            Ok(unsent.len())
+        }
+        // The user is gone too, adding it back with its chats restores both in one request
+        Err(ApiError::NotFound) => {
+            let chat_log: Vec<Chat> = log.chats.iter().map(|stamped| stamped.chat.clone()).collect();
+            let count = chat_log.len();
+            client.register(&User { name: log.name.clone(), id: id.to_string(), chat_log, keys: None })?;
+            Ok(count)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Puts our key announcement in our profile. The Go server drops the field, so there it goes
+// in our own chat log instead. Returns false when the profile already holds other keys, the
+// server keeps whichever it got first.
+fn publish(client: &Client, me: User) -> Result<bool, ApiError> {
+    client.register(&me)?;
+    let ours = me.keys.as_deref().and_then(|keys| signing::announced_by(&me.id, keys));
+    if let Some(keys) = client.get_user(&me.id)?.keys {
+        return Ok(signing::announced_by(&me.id, &keys) == ours);
+    }
+    let chat = Chat { chat: me.keys.unwrap_or_default(), user_id: me.id.clone(), user_name: me.name };
+    client.send_chat(&me.id, &chat).map(|_| true)
+}
@@ -0,0 +1,124 @@
+// src/auth.rs
+
+use std::{env, fs, io, path::{Path, PathBuf}};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::config::Profile;
+use crate::store::data_dir;
+
+// Tokens this close to expiring are refreshed before they are used
+const REFRESH_MARGIN: Duration = Duration::seconds(30);
+
+/// A name and secret to log in with, from MEOW_LOGIN and MEOW_SECRET
+#[derive(Clone)]
+pub struct Credentials {
+    pub name: String,
+    pub secret: String,
+}
+
+/// The bearer token the server handed out, kept per profile so it outlives a restart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// What `POST /auth/login` and `POST /auth/refresh` answer
+#[derive(Deserialize)]
+pub struct Grant {
+    pub token: String,
+    #[serde(default)]
+    pub expires_in: Option<i64>, // Seconds, tokens without one never expire
+}
+
+impl Session {
+    pub fn from_grant(grant: Grant) -> Session {
+        Session { token: grant.token, expires: grant.expires_in.map(|seconds| Utc::now() + Duration::seconds(seconds)) }
+    }
+
+    /// Whether the token should be refreshed before it is sent
+    pub fn expiring(&self) -> bool {
+        self.expires.is_some_and(|expires| expires - REFRESH_MARGIN <= Utc::now())
+    }
+}
+
+/// Everything the client knows about logging in to one profile
+pub struct Auth {
+    path: PathBuf,
+    pub session: Option<Session>,
+    pub credentials: Option<Credentials>,
+}
+
+impl Auth {
+    /// MEOW_TOKEN wins over a saved session, which is only used when there is one
+    pub fn load(profile: &Profile) -> Auth {
+        let path = data_dir().join(format!("{}.session.json", profile.name));
+        let session = match env::var("MEOW_TOKEN") {
+            Ok(token) if !token.is_empty() => Some(Session { token, expires: None }),
+            _ => fs::read(&path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()),
+        };
+        let credentials = match (env::var("MEOW_LOGIN"), env::var("MEOW_SECRET")) {
+            (Ok(name), Ok(secret)) if !secret.is_empty() => Some(Credentials { name, secret }),
+            _ => None,
+        };
+        Auth { path, session, credentials }
+    }
+
+    /// Keeps a new session, on disk as well so the next run does not have to log in
+    pub fn keep(&mut self, session: Session) -> io::Result<()> {
+        self.session = Some(session);
+        save_private(&self.path, &self.session)
+    }
+
+    /// Drops a session the server no longer accepts
+    pub fn forget(&mut self) {
+        self.session = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Tokens are as good as a password, only we may read them. The temporary file is made that
+// way, so the token is never readable by others, not even until the rename.
+#[cfg(unix)]
+fn save_private(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    let _ = fs::remove_file(&temporary); // Left behind by a crash, and maybe by an older version that made it readable
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&temporary)?;
+    file.write_all(&serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(not(unix))]
+fn save_private(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    crate::store::save_json(path, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn sessions_are_only_readable_by_us() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("meow-session-test-{}", std::process::id())).join("test.session.json");
+        let mut auth = Auth { path: path.clone(), session: None, credentials: None };
+        auth.keep(Session { token: "secret".to_string(), expires: None }).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // A temporary file left behind readable is replaced rather than written into
+        fs::write(path.with_extension("json.tmp"), "").unwrap();
+        fs::set_permissions(path.with_extension("json.tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+        auth.keep(Session { token: "again".to_string(), expires: None }).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let kept: Option<Session> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(kept.unwrap().token, "again");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+// src/cli.rs
+
+use std::{collections::HashMap, fs, io::{self, BufRead, Write}};
+use crate::config::Profile;
+use crate::e2e::Identity;
+use crate::export::{self, read_archive, Format, FORMATS};
+use crate::history::History;
+use crate::request::Client;
+use crate::generate_id;
+
+const USAGE: &str = "usage:
+  meow-cli [--insecure]    start the chat client, --insecure skips certificate checks
+  meow-cli export --user <id> --format json|md|txt|html [--out <file>]
+  meow-cli import <file> [--dry-run] [--yes]
+  meow-cli login --name <name>  reads the secret from stdin
+  meow-cli room list|create|join|leave [<room>]  rooms, joined and left as this machine's user
+  meow-cli daemon [--name <name>]  sync in the background, serving TUIs started with attach
+  meow-cli attach          start the chat client on the daemon, Ctrl+Q leaves the daemon running";
+
+/// Runs the subcommand named on the command line, without starting the TUI
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args[0].as_str() {
+        "export" => export(&parse(&args[1..], &[])?),
+        "import" => import(&parse(&args[1..], &["dry-run", "yes"])?),
+        "login" => login(&parse(&args[1..], &[])?),
+        "daemon" => daemon(&parse(&args[1..], &[])?),
+        "room" => room(&parse(&args[1..], &[])?),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(format!("unknown command '{}'\n{}", other, USAGE)),
+    }
+}
+
+// The arguments after the subcommand
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, String>,
+    switches: Vec<String>,
+}
+
+// Splits arguments into `--name value` flags, the bare `--switch`es listed in `switches` and the rest
+fn parse(args: &[String], switches: &[&str]) -> Result<Args, String> {
+    let mut parsed = Args { positional: Vec::new(), flags: HashMap::new(), switches: Vec::new() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some(name) if switches.contains(&name) => parsed.switches.push(name.to_string()),
+            Some(name) => {
+                let value = args.next().ok_or_else(|| format!("--{} needs a value", name))?;
+                parsed.flags.insert(name.to_string(), value.clone());
+            }
+            None => parsed.positional.push(arg.clone()),
+        }
+    }
+    Ok(parsed)
+}
+
+// Writes a conversation out, fresh from the server when it answers, else from the local history
+fn export(args: &Args) -> Result<(), String> {
+    if let Some(arg) = args.positional.first() {
+        return Err(format!("unexpected argument '{}'\n{}", arg, USAGE));
+    }
+    let flags = &args.flags;
+    let id = flags.get("user").ok_or_else(|| format!("export needs --user <id>\n{}", USAGE))?;
+    let format = flags.get("format").map_or("json", String::as_str);
+    let format = Format::parse(format).ok_or_else(|| format!("unknown format '{}', try one of {}", format, FORMATS))?;
+
+    let profile = Profile::from_env();
+    let mut history = History::open(&profile);
+    let client = Client::new(&profile);
+    let me = generate_id();
+    // Someone else's conversation with us is our DM thread with them, where the server has those
+    let fetched = client.get_user(id).and_then(|user| {
+        if user.id == me {
+            return Ok((user.name, user.chat_log, false));
+        }
+        client.get_conversation(&me, id).map(|(chat_log, thread)| (user.name, chat_log, thread))
+    });
+    match fetched {
+        Ok((name, chat_log, thread)) => {
+            if history.record(id, &name, &chat_log) | (thread && history.mark_shared(id)) {
+                history.save().map_err(|error| format!("could not save the local history: {}", error))?;
+            }
+        }
+        Err(error) if history.conversations.contains_key(id) => {
+            eprintln!("meow-cli: could not fetch {} ({}), exporting the local copy", id, error);
+        }
+        Err(error) => return Err(format!("could not fetch {}: {}", id, error)),
+    }
+
+    // Our key pair opens the sealed chats for the readable formats
+    let identity = Identity::load(&me).ok();
+    let log = &history.conversations[id];
+    let text = export::render(format, id, &log.name, &log.chats, identity.as_ref(), &me);
+    match flags.get("out") {
+        Some(path) => fs::write(path, text).map_err(|error| format!("could not write {}: {}", path, error)),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+// Loads an archive or user list into the local history, showing what would change before writing it
+fn import(args: &Args) -> Result<(), String> {
+    let [path] = args.positional.as_slice() else {
+        return Err(format!("import needs exactly one file\n{}", USAGE));
+    };
+    let bytes = fs::read(path).map_err(|error| format!("could not read {}: {}", path, error))?;
+    let conversations = read_archive(&bytes)?;
+
+    let profile = Profile::from_env();
+    let mut history = History::open(&profile);
+
+    // Work out what is new before touching anything
+    let mut total = 0;
+    let mut plan = Vec::new();
+    for conversation in conversations {
+        let missing = history.missing(&conversation.id, &conversation.chats);
+        println!(
+            "{} ({}): {} new, {} already here",
+            conversation.name,
+            conversation.id,
+            missing.len(),
+            conversation.chats.len() - missing.len()
+        );
+        total += missing.len();
+        plan.push((conversation, missing));
+    }
+
+    if total == 0 {
+        println!("Nothing to import");
+        return Ok(());
+    }
+    if args.switches.iter().any(|switch| switch == "dry-run") {
+        println!("Dry run, {} chats would be imported into profile {}", total, profile.name);
+        return Ok(());
+    }
+    if !args.switches.iter().any(|switch| switch == "yes") && !ask(&format!("Import {} chats into profile {}?", total, profile.name)) {
+        println!("Nothing imported");
+        return Ok(());
+    }
+
+    for (conversation, missing) in plan {
+        history.add(&conversation.id, &conversation.name, missing);
+    }
+    history.save().map_err(|error| format!("could not save the local history: {}", error))?;
+    println!("Imported {} chats", total);
+    Ok(())
+}
+
+// Logs in to the server and saves the token for the TUI and the other subcommands
+fn login(args: &Args) -> Result<(), String> {
+    let name = args.flags.get("name").ok_or_else(|| format!("login needs --name <name>\n{}", USAGE))?;
+    let mut secret = String::new();
+    io::stdin().lock().read_line(&mut secret).map_err(|error| format!("could not read the secret: {}", error))?;
+
+    let profile = Profile::from_env();
+    Client::new(&profile).login(name, secret.trim_end_matches(['\r', '\n'])).map_err(|error| format!("could not log in as {}: {}", name, error))?;
+    println!("Logged in to {} as {}", profile.name, name);
+    Ok(())
+}
+
+// Lists, makes, joins or leaves rooms as the user the TUI signs up as on this machine
+fn room(args: &Args) -> Result<(), String> {
+    let client = Client::new(&Profile::from_env());
+    let me = generate_id();
+    let positional: Vec<&str> = args.positional.iter().map(|arg| arg.trim_start_matches('#')).collect();
+    match positional.as_slice() {
+        ["list"] => {
+            let rooms = client.get_rooms().map_err(|error| format!("could not list the rooms: {}", error.about_room()))?;
+            if rooms.is_empty() {
+                println!("No rooms yet");
+            }
+            for room in rooms {
+                let joined = if room.members.contains(&me) { ", joined" } else { "" };
+                println!("#{} ({}): {} members{}", room.name, room.id, room.members.len(), joined);
+            }
+        }
+        ["create", name] => {
+            let room = client.create_room(name, &me).map_err(|error| format!("could not create #{}: {}", name, error.about_room()))?;
+            println!("Created #{} ({})", room.name, room.id);
+        }
+        ["join", name] => {
+            let room = client.join_room(name, &me).map_err(|error| format!("could not join #{}: {}", name, error.about_room()))?;
+            println!("Joined #{}, {} members", room.name, room.members.len());
+        }
+        ["leave", name] => {
+            let room = client.leave_room(name, &me).map_err(|error| format!("could not leave #{}: {}", name, error.about_room()))?;
+            println!("Left #{}", room.name);
+        }
+        _ => return Err(format!("room needs list, or create, join or leave with a room name\n{}", USAGE)),
+    }
+    Ok(())
+}
+
+// Runs until killed, named after --name or the login user
+#[cfg(unix)]
+fn daemon(args: &Args) -> Result<(), String> {
+    let name = args.flags.get("name").cloned().or_else(|| std::env::var("USER").ok().filter(|user| !user.is_empty())).ok_or_else(|| format!("daemon needs --name <name>\n{}", USAGE))?;
+    crate::daemon::run(name)
+}
+
+#[cfg(not(unix))]
+fn daemon(_args: &Args) -> Result<(), String> {
+    Err("the daemon needs Unix sockets".to_string())
+}
+
+// Asks a yes/no question on the terminal, only y accepts
+fn ask(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    matches!(answer.trim(), "y" | "Y")
+}
@@ -0,0 +1,43 @@
+// src/config.rs
+
+use std::{env, path::PathBuf};
+
+const DEFAULT_PROFILE: &str = "local";
+const DEFAULT_SERVER: &str = "http://localhost:4343";
+
+/// Which chat server to talk to, picked with MEOW_PROFILE and MEOW_SERVER (an http(s):// URL,
+/// unix:<socket path> or memory:), and how to trust it:
+/// an extra CA bundle in MEOW_CA, a client certificate and key in MEOW_CLIENT_CERT and
+/// MEOW_CLIENT_KEY, or no checks at all with MEOW_INSECURE=1 (or --insecure). MEOW_PROXY
+/// sends everything through an http:// or socks5:// proxy.
+#[derive(Clone)]
+pub struct Profile {
+    pub name: String,
+    pub base_url: String,
+    pub ca_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub insecure: bool,
+    pub proxy: Option<String>,
+    pub attached: bool, // Talking to a daemon, which keeps the local history itself
+}
+
+// A path from the environment, unset and empty both mean none
+fn path_var(name: &str) -> Option<PathBuf> {
+    env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
+
+impl Profile {
+    pub fn from_env() -> Profile {
+        Profile {
+            name: env::var("MEOW_PROFILE").unwrap_or_else(|_| DEFAULT_PROFILE.to_string()),
+            base_url: env::var("MEOW_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.to_string()),
+            ca_file: path_var("MEOW_CA"),
+            client_cert: path_var("MEOW_CLIENT_CERT"),
+            client_key: path_var("MEOW_CLIENT_KEY"),
+            insecure: env::var("MEOW_INSECURE").is_ok_and(|value| value == "1"),
+            proxy: env::var("MEOW_PROXY").ok().filter(|proxy| !proxy.is_empty()),
+            attached: false,
+        }
+    }
+}
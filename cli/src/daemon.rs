@@ -0,0 +1,376 @@
+// src/daemon.rs
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::unix::{fs::PermissionsExt, net::{UnixListener, UnixStream}},
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use crate::config::Profile;
+use crate::history::History;
+use crate::mention::mentions;
+use crate::notify::Notifier;
+use crate::request::{no_route, ApiError, Chat, Client, User};
+use crate::serve;
+use crate::status::Connection;
+use crate::store::{data_dir, save_json, Store};
+use crate::sync::{self, SyncCommand, SyncEvent};
+use crate::api::{Request, Response};
+use crate::generate_id;
+
+// An attached TUI polls every couple of seconds, while one has been heard from this recently
+// it shows new messages itself and the daemon keeps quiet
+const ATTACHED_WINDOW: Duration = Duration::from_secs(6);
+
+/// Where the daemon for a profile listens
+pub fn socket_path(profile: &Profile) -> PathBuf {
+    data_dir().join(format!("{}.sock", profile.name))
+}
+
+/// Points a profile at the daemon running for it, for `meow-cli attach`
+pub fn attach(profile: &mut Profile) -> Result<(), String> {
+    let path = socket_path(profile);
+    UnixStream::connect(&path).map_err(|error| format!("no daemon for profile {} at {} ({}), start one with meow-cli daemon", profile.name, path.display(), error))?;
+    profile.base_url = format!("unix:{}", path.display());
+    profile.attached = true;
+    Ok(())
+}
+
+// A chat posted while the server could not be reached, sent once it is back
+#[derive(Clone, Serialize, Deserialize)]
+struct Queued {
+    to: String, // The user it is for
+    #[serde(default)]
+    path: Option<String>, // The route it was posted to, outboxes from before DM threads only had /user/{to}/chat
+    body: serde_json::Value,
+}
+
+impl Queued {
+    fn post(&self, path: &str) -> Request {
+        Request { method: "POST".to_string(), path: path.to_string(), token: None, body: Some(serde_json::to_vec(&self.body).unwrap_or_default()) }
+    }
+}
+
+// What the connections and the sync loop share
+struct Shared {
+    client: Client,
+    sync: Sender<SyncCommand>,
+    users: HashMap<String, User>, // The last copy of every watched user, answered from when the server is away
+    directory: Vec<User>,
+    outbox: Vec<Queued>,
+    outbox_path: PathBuf,
+    heard: Option<Instant>, // When a TUI last asked for something
+}
+
+impl Shared {
+    fn save_outbox(&self) {
+        if let Err(error) = save_json(&self.outbox_path, &self.outbox) {
+            log(&format!("could not save the outbox: {}", error));
+        }
+    }
+
+    // Sends queued chats in order, stopping at the first that still can not go out
+    fn flush(&mut self) {
+        let before = self.outbox.len();
+        while let Some(queued) = self.outbox.first() {
+            let inbox = format!("/user/{}/chat", queued.to);
+            let path = queued.path.as_deref().unwrap_or(&inbox);
+            let mut result = self.client.forward(&queued.post(path));
+            // A server without DM threads files direct messages under whoever they are for, like Client::send_to.
+            // One that has them but does not know the sender or peer gets nothing filed anywhere else.
+            if path.starts_with("/dm/") && result.as_ref().is_ok_and(no_route) {
+                result = self.client.forward(&queued.post(&inbox));
+            }
+            match result {
+                Ok(response) => {
+                    if !(200..300).contains(&response.status) {
+                        log(&format!("gave up on a queued chat to {}: {}", queued.to, String::from_utf8_lossy(&response.body).trim()));
+                    }
+                    self.outbox.remove(0);
+                }
+                Err(_) => break,
+            }
+        }
+        if self.outbox.len() != before {
+            log(&format!("sent {} queued chats, {} left", before - self.outbox.len(), self.outbox.len()));
+            self.save_outbox();
+        }
+    }
+}
+
+fn log(text: &str) {
+    eprintln!("{} meow-cli daemon: {}", Local::now().format("%H:%M:%S"), text);
+}
+
+/// Runs sync, the outbox, the local history and notifications without a TUI, and serves the
+/// chat API on a Unix socket for TUIs started with `meow-cli attach`
+pub fn run(name: String) -> Result<(), String> {
+    let profile = Profile::from_env();
+    let path = socket_path(&profile);
+
+    // A socket nobody answers on is left over from a daemon that did not get to clean up
+    if UnixStream::connect(&path).is_ok() {
+        return Err(format!("a daemon is already running for profile {} at {}", profile.name, path.display()));
+    }
+    let _ = fs::remove_file(&path);
+    fs::create_dir_all(data_dir()).map_err(|error| format!("could not create {}: {}", data_dir().display(), error))?;
+    let listener = UnixListener::bind(&path).map_err(|error| format!("could not listen on {}: {}", path.display(), error))?;
+    // Whoever can connect gets our login on the server, so only we may
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).map_err(|error| format!("could not protect {}: {}", path.display(), error))?;
+
+    let me = User { name, id: generate_id(), chat_log: Vec::new(), keys: None };
+    let client = Client::new(&profile);
+    let (sync, events) = sync::spawn(client.clone(), me.clone());
+
+    let outbox_path = data_dir().join(format!("{}.outbox.json", profile.name));
+    let outbox = fs::read(&outbox_path).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok()).unwrap_or_default();
+    let shared = Arc::new(Mutex::new(Shared { client, sync, users: HashMap::new(), directory: Vec::new(), outbox, outbox_path, heard: None }));
+
+    log(&format!("listening on {} as {} for {}", path.display(), me.name, profile.base_url));
+    let accepting = shared.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let shared = accepting.clone();
+            thread::spawn(move || serve::connection(stream, &|request| handle(&shared, request)));
+        }
+    });
+
+    // Everything the sync task finds goes into the history, and new chats to us notify while no TUI is attached
+    let mut history = History::open(&profile);
+    let notifier = Notifier::from_env();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for event in events {
+        match event {
+            SyncEvent::Connection(connection) => {
+                let text = match &connection {
+                    Connection::Connecting => "connecting".to_string(),
+                    Connection::Connected => "connected".to_string(),
+                    Connection::Reconnecting { attempt, error } => format!("reconnecting #{}: {}", attempt, error),
+                    Connection::Offline { error } => format!("offline: {}", error),
+                };
+                log(&text);
+                if connection == Connection::Connected {
+                    shared.lock().unwrap().flush();
+                }
+            }
+            SyncEvent::Users(users) => shared.lock().unwrap().directory = users,
+            SyncEvent::User(user) => {
+                let fresh = seen.get(&user.id).map_or(&[][..], |count| user.chat_log.get(*count..).unwrap_or_default());
+                if user.id == me.id && !fresh.is_empty() {
+                    notify(&shared, &notifier, &profile, &me, (&me.id, &me.name), fresh);
+                }
+                seen.insert(user.id.clone(), user.chat_log.len());
+
+                if history.record(&user.id, &user.name, &user.chat_log) {
+                    if let Err(error) = history.save() {
+                        log(&format!("could not save the history: {}", error));
+                    }
+                }
+                shared.lock().unwrap().users.insert(user.id.clone(), user);
+            }
+            // Attached TUIs reach their rooms through us, the daemon itself follows none
+            SyncEvent::Room(_) => {}
+            SyncEvent::Thread { peer, chat_log } => {
+                let name = shared.lock().unwrap().directory.iter().find(|user| user.id == peer).map_or_else(|| peer.clone(), |user| user.name.clone());
+                let fresh = seen.get(&peer).map_or(&[][..], |count| chat_log.get(*count..).unwrap_or_default());
+                let theirs: Vec<Chat> = fresh.iter().filter(|chat| chat.user_id != me.id).cloned().collect();
+                if !theirs.is_empty() {
+                    notify(&shared, &notifier, &profile, &me, (&peer, &name), &theirs);
+                }
+                seen.insert(peer.clone(), chat_log.len());
+
+                if history.record(&peer, &name, &chat_log) | history.mark_shared(&peer) {
+                    if let Err(error) = history.save() {
+                        log(&format!("could not save the history: {}", error));
+                    }
+                }
+            }
+            SyncEvent::Reregistered => log("the server lost its data, signed up again"),
+            SyncEvent::LoginNeeded => log("the server wants a login, run meow-cli login --name <name>"),
+            SyncEvent::Missing(id) => log(&format!("the server has no user with id {}", id)),
+        }
+    }
+    Ok(())
+}
+
+// Tells about new chats in our inbox or the DM thread with (`id`, `name`), unless a TUI is around
+// to do it or the settings say not to
+fn notify(shared: &Mutex<Shared>, notifier: &Notifier, profile: &Profile, me: &User, (id, name): (&str, &str), fresh: &[Chat]) {
+    if shared.lock().unwrap().heard.is_some_and(|heard| heard.elapsed() < ATTACHED_WINDOW) {
+        return;
+    }
+    // Read the settings each time so mutes and quiet hours set from a TUI apply straight away
+    let settings = Store::open(profile).notify;
+    let mentioned = fresh.iter().any(|chat| mentions(&chat.chat, &me.name));
+    if !settings.allows(id, mentioned) {
+        return;
+    }
+    let body = match fresh {
+        [chat] => chat.chat.clone(),
+        chats => format!("{} new messages", chats.len()),
+    };
+    let _ = notifier.notify(&mut io::stderr(), &format!("meow - {}", name), &body);
+}
+
+// Passes a TUI's request on to the server. Reads fall back to the last copy when it can not be
+// reached, and chats are queued in the outbox to go out once it is back.
+fn handle(shared: &Mutex<Shared>, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    let posting = request.method == "POST" && matches!(segments.as_slice(), ["user", _, "chat"] | ["dm", _, _]);
+
+    let (client, waiting) = {
+        let mut shared = shared.lock().unwrap();
+        shared.heard = Some(Instant::now());
+
+        // Keep following whatever a TUI looks at after it detaches
+        if let (Some(&"user"), Some(id), "GET") = (segments.first(), segments.get(1), request.method.as_str()) {
+            if !id.is_empty() && !matches!(*id, "name" | "check") {
+                let _ = shared.sync.send(SyncCommand::Watch(id.to_string()));
+            }
+        }
+
+        // Later chats wait behind queued ones so they arrive in order
+        if posting && !shared.outbox.is_empty() {
+            shared.flush();
+        }
+        (shared.client.clone(), posting && !shared.outbox.is_empty())
+    };
+
+    // The lock is not held while the server is asked, so a slow server holds up no one else
+    let result = if waiting { Err(ApiError::Transport("chats are still queued".to_string())) } else { client.forward(request) };
+
+    let mut shared = shared.lock().unwrap();
+    match result {
+        Ok(response) => response,
+        Err(error) => match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["user", ""]) if !shared.directory.is_empty() => json(&shared.directory),
+            ("GET", ["user", id]) if shared.users.contains_key(*id) => json(&shared.users[*id]),
+            ("GET", ["user", id, "chats"]) if shared.users.contains_key(*id) => json(&shared.users[*id].chat_log),
+            ("POST", ["user", id, "chat"]) => queue(&mut shared, id, id, request),
+            ("POST", ["dm", id, peer]) => queue(&mut shared, id, peer, request),
+            _ => Response { status: 502, body: format!("{}\n", error).into_bytes() },
+        },
+    }
+}
+
+// Keeps a chat to `to` for later and answers the way the server would have, with the chat filed
+// as from `from`: the owner of the log for /user/{id}/chat, the sender for a direct message
+fn queue(shared: &mut Shared, from: &str, to: &str, request: &Request) -> Response {
+    let Some(body) = request.body.as_deref().and_then(|body| serde_json::from_slice::<serde_json::Value>(body).ok()) else {
+        return Response { status: 400, body: b"Invalid input\n".to_vec() };
+    };
+    let known = shared.users.get(from).or_else(|| shared.directory.iter().find(|user| user.id == from));
+    let name = known.map(|user| user.name.clone()).unwrap_or_default();
+    let chat = Chat { chat: body["chat"].as_str().unwrap_or_default().to_string(), user_id: from.to_string(), user_name: name };
+    shared.outbox.push(Queued { to: to.to_string(), path: Some(request.path.clone()), body });
+    shared.save_outbox();
+    log(&format!("the server is away, queued a chat to {} ({} waiting)", to, shared.outbox.len()));
+    json(&chat)
+}
+
+fn json(value: &impl Serialize) -> Response {
+    Response { status: 200, body: serde_json::to_vec(value).unwrap_or_default() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicBool, Ordering}, mpsc::channel};
+    use meow_common::memory::Memory;
+    use super::*;
+    use crate::request::Thread;
+    use meow_cli::transport::Transport;
+
+    // The in-process server, which can be made unreachable
+    struct Flaky {
+        memory: Memory,
+        up: Arc<AtomicBool>,
+    }
+
+    impl Transport for Flaky {
+        fn send(&self, request: &Request) -> Result<Response, ApiError> {
+            match self.up.load(Ordering::SeqCst) {
+                true => Ok(self.memory.handle(request)),
+                false => Err(ApiError::Transport("connection refused".to_string())),
+            }
+        }
+    }
+
+    fn post(path: &str, chat: &str) -> Request {
+        Request { method: "POST".to_string(), path: path.to_string(), token: None, body: Some(serde_json::to_vec(&serde_json::json!({ "chat": chat })).unwrap()) }
+    }
+
+    // A daemon in front of a server that knows `users` and can be taken away, with its outbox under `name`
+    fn start(name: &str, users: &[(&str, &str)]) -> (Client, Arc<AtomicBool>, Mutex<Shared>, PathBuf) {
+        let up = Arc::new(AtomicBool::new(true));
+        let mut profile = Profile::from_env();
+        profile.name = format!("daemon-test-{}-{}", name, std::process::id());
+        let client = Client::with_transport(&profile, Arc::new(Flaky { memory: Memory::seeded(), up: up.clone() }));
+        for (name, id) in users {
+            client.register(&User { name: name.to_string(), id: id.to_string(), chat_log: Vec::new(), keys: None }).unwrap();
+        }
+        let outbox_path = std::env::temp_dir().join(format!("meow-daemon-test-{}-{}", name, std::process::id())).join("outbox.json");
+        let shared = Mutex::new(Shared {
+            client: client.clone(),
+            sync: channel().0,
+            users: HashMap::new(),
+            directory: client.get_users().unwrap(),
+            outbox: Vec::new(),
+            outbox_path: outbox_path.clone(),
+            heard: None,
+        });
+        (client, up, shared, outbox_path)
+    }
+
+    #[test]
+    fn direct_messages_wait_in_the_outbox() {
+        let (client, up, shared, outbox_path) = start("outbox", &[("alice", "a1"), ("bob", "b2")]);
+
+        up.store(false, Ordering::SeqCst);
+        let response = handle(&shared, &post("/dm/a1/b2", "first"));
+        assert_eq!(response.status, 200);
+        let answered: Chat = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!((answered.user_id.as_str(), answered.user_name.as_str()), ("a1", "alice"));
+        assert_eq!(handle(&shared, &post("/user/b2/chat", "second")).status, 200);
+        let saved: Vec<Queued> = serde_json::from_slice(&fs::read(&outbox_path).unwrap()).unwrap();
+        let routes: Vec<Option<&str>> = saved.iter().map(|queued| queued.path.as_deref()).collect();
+        assert_eq!(routes, [Some("/dm/a1/b2"), Some("/user/b2/chat")]);
+
+        up.store(true, Ordering::SeqCst);
+        shared.lock().unwrap().flush();
+        assert!(shared.lock().unwrap().outbox.is_empty());
+        let thread = client.get_thread("a1", "b2").unwrap();
+        assert_eq!(thread.users, Thread::between("a1", "b2"));
+        assert_eq!(thread.chat_log.len(), 1);
+        assert_eq!((thread.chat_log[0].user_id.as_str(), thread.chat_log[0].chat.as_str()), ("a1", "first"));
+        let inbox: Vec<String> = client.get_chats("b2").unwrap().into_iter().map(|chat| chat.chat).collect();
+        assert_eq!(inbox, ["second"]);
+        fs::remove_dir_all(outbox_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn direct_messages_from_strangers_are_not_filed_elsewhere() {
+        let (client, up, shared, outbox_path) = start("stranger", &[("bob", "b2")]);
+        up.store(false, Ordering::SeqCst);
+        assert_eq!(handle(&shared, &post("/dm/z9/b2", "who am i")).status, 200);
+
+        // The server has threads but no z9, so the chat is given up rather than posted to bob's log
+        up.store(true, Ordering::SeqCst);
+        shared.lock().unwrap().flush();
+        assert!(shared.lock().unwrap().outbox.is_empty());
+        assert!(client.get_chats("b2").unwrap().is_empty());
+        fs::remove_dir_all(outbox_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn outboxes_from_before_threads_still_load() {
+        let queued: Queued = serde_json::from_str(r#"{"to": "b2", "body": {"chat": "hi"}}"#).unwrap();
+        assert!(queued.path.is_none());
+        assert_eq!(queued.to, "b2");
+    }
+}
@@ -0,0 +1,328 @@
+// src/e2e.rs
+
+use std::{fs, io, path::PathBuf};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{aead::{Aead, KeyInit, Payload}, ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signer, SigningKey};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::request::Chat;
+use crate::signing;
+use crate::store::data_dir;
+
+// Public keys are published as an announcement starting with this tag, in the owner's profile or, as the
+// Go server drops any field beyond a name and an id, as a chat in their own chat log. Version 1 only had
+// the encryption key and is no longer taken, version 2 adds the signing key after a ':' and is signed with it.
+const KEY_TAG: &str = "meow-key:";
+// Sealed chats are a JSON envelope behind this tag in the `chat` field
+const SEAL_TAG: &str = "meow-e2e:1:";
+// Mixed into every key derivation so keys made here are never reused for anything else
+const KDF_INFO: &[u8] = b"meow-e2e-v1";
+
+/// How a chat in the history pane was protected on the way
+#[derive(Clone, Copy, PartialEq)]
+pub enum Lock {
+    Plain,
+    Sealed,
+    KeyChanged, // Sealed, but with a key that is not the one pinned for the sender
+    Unreadable,
+}
+
+/// Our X25519 key pair for sealing and Ed25519 key pair for signing, made on first use and kept
+/// in the data directory under our user id
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+    signing: SigningKey,
+}
+
+/// The public keys someone published
+#[derive(Clone, PartialEq)]
+pub struct Published {
+    pub seal: String,
+    pub sign: Option<String>,
+}
+
+// Random bytes straight from the operating system
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).expect("the operating system has no random numbers");
+    bytes
+}
+
+// Reads a secret key from the data directory, making and saving a new one the first time
+fn load_secret(id: &str, kind: &str) -> io::Result<[u8; 32]> {
+    let path = data_dir().join("keys").join(format!("{}.{}", id, kind));
+    match fs::read(&path) {
+        Ok(bytes) => bytes.try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a key", path.display()))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let secret = random::<32>();
+            save_secret(&path, &secret)?;
+            Ok(secret)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+impl Identity {
+    /// Loads the key pairs of the user `id`, making and saving new ones the first time
+    pub fn load(id: &str) -> io::Result<Identity> {
+        let secret = StaticSecret::from(load_secret(id, "x25519")?);
+        let signing = SigningKey::from_bytes(&load_secret(id, "ed25519")?);
+        Ok(Identity { public: PublicKey::from(&secret), secret, signing })
+    }
+
+    /// Our public encryption key as it is published and pinned
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    /// Our public keys as they are published and pinned
+    pub fn published(&self) -> Published {
+        Published { seal: self.public_key(), sign: Some(BASE64.encode(self.signing.verifying_key().as_bytes())) }
+    }
+
+    /// The announcement that publishes our public keys, signed by `owner`, our user id
+    pub fn announcement(&self, owner: &str) -> String {
+        let body = format!("{}2:{}:{}", KEY_TAG, self.public_key(), self.published().sign.unwrap_or_default());
+        signing::sign(self, owner, owner, &body)
+    }
+
+    /// Signs `message` with our signing key
+    pub fn sign(&self, message: &[u8]) -> String {
+        BASE64.encode(self.signing.sign(message).to_bytes())
+    }
+
+    // The key that wraps a message key between us and `other`, the same from either side
+    fn wrapping_key(&self, other: &PublicKey, sender: &PublicKey, recipient: &PublicKey) -> Key {
+        let shared = self.secret.diffie_hellman(other);
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(sender.as_bytes());
+        info.extend_from_slice(recipient.as_bytes());
+
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(None, shared.as_bytes()).expand(&info, &mut key).expect("32 bytes is a valid HKDF length");
+        key
+    }
+}
+
+#[cfg(unix)]
+fn save_secret(path: &PathBuf, secret: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(secret)
+}
+
+#[cfg(not(unix))]
+fn save_secret(path: &PathBuf, secret: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(path, secret)
+}
+
+/// The public keys published by a key announcement chat
+pub fn announced_keys(text: &str) -> Option<Published> {
+    let rest = text.strip_prefix(KEY_TAG)?;
+    let published = if let Some(seal) = rest.strip_prefix("1:") {
+        Published { seal: seal.to_string(), sign: None }
+    } else {
+        let (seal, sign) = rest.strip_prefix("2:")?.split_once(':')?;
+        Published { seal: seal.to_string(), sign: Some(sign.to_string()) }
+    };
+    parse_key(&published.seal).map(|_| published)
+}
+
+fn parse_key(text: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = BASE64.decode(text).ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+// The message key wrapped for one recipient
+#[derive(Serialize, Deserialize)]
+struct Wrapped {
+    id: String,
+    nonce: String,
+    key: String,
+}
+
+// What a sealed chat carries. The sender's id and name are inside because the Go server
+// overwrites the ones on the chat with those of the conversation it was posted to.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    from: String,
+    name: String,
+    key: String,
+    nonce: String,
+    body: String,
+    to: Vec<Wrapped>,
+}
+
+/// Seals `text` so that only the `recipients` (id and public key) can read it. The sender's id is
+/// bound to the ciphertext so it can not be swapped for another without the message failing to open.
+pub fn seal(identity: &Identity, from: &str, name: &str, text: &str, recipients: &[(String, String)]) -> Result<String, String> {
+    let message_key = Key::from(random::<32>());
+    let nonce = random::<12>();
+    let body = ChaCha20Poly1305::new(&message_key)
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: text.as_bytes(), aad: from.as_bytes() })
+        .map_err(|_| "could not encrypt".to_string())?;
+
+    let mut to = Vec::new();
+    for (id, key) in recipients {
+        let public = parse_key(key).ok_or_else(|| format!("the key for {} is broken", id))?;
+        let wrap_nonce = random::<12>();
+        let wrapped = ChaCha20Poly1305::new(&identity.wrapping_key(&public, &identity.public, &public))
+            .encrypt(Nonce::from_slice(&wrap_nonce), message_key.as_slice())
+            .map_err(|_| "could not encrypt".to_string())?;
+        to.push(Wrapped { id: id.clone(), nonce: BASE64.encode(wrap_nonce), key: BASE64.encode(wrapped) });
+    }
+
+    let envelope = Envelope {
+        from: from.to_string(),
+        name: name.to_string(),
+        key: identity.public_key(),
+        nonce: BASE64.encode(nonce),
+        body: BASE64.encode(body),
+        to,
+    };
+    Ok(format!("{}{}", SEAL_TAG, serde_json::to_string(&envelope).map_err(|error| error.to_string())?))
+}
+
+/// What a chat turned out to be
+pub enum Opened {
+    Plain,
+    Sealed { chat: Chat, key: String },
+    Unreadable(String),
+}
+
+/// Opens a sealed chat addressed to `me`. The chat comes back with the sender from inside the envelope
+/// and the key it was sealed with, which the caller checks against the key pinned for that sender.
+pub fn open(identity: Option<&Identity>, me: &str, text: &str) -> Opened {
+    let Some(sealed) = text.strip_prefix(SEAL_TAG) else { return Opened::Plain };
+    let Ok(envelope) = serde_json::from_str::<Envelope>(sealed) else { return Opened::Unreadable("damaged envelope".to_string()) };
+    let Some(identity) = identity else { return Opened::Unreadable("no key pair here".to_string()) };
+    let Some(wrapped) = envelope.to.iter().find(|wrapped| wrapped.id == me) else { return Opened::Unreadable("not sealed for us".to_string()) };
+    let Some(sender) = parse_key(&envelope.key) else { return Opened::Unreadable("damaged envelope".to_string()) };
+
+    let decode = |text: &str| BASE64.decode(text).ok();
+    let (Some(wrap_nonce), Some(wrapped_key), Some(nonce), Some(body)) = (decode(&wrapped.nonce), decode(&wrapped.key), decode(&envelope.nonce), decode(&envelope.body)) else {
+        return Opened::Unreadable("damaged envelope".to_string());
+    };
+    if wrap_nonce.len() != 12 || nonce.len() != 12 {
+        return Opened::Unreadable("damaged envelope".to_string());
+    }
+
+    let message_key = ChaCha20Poly1305::new(&identity.wrapping_key(&sender, &sender, &identity.public))
+        .decrypt(Nonce::from_slice(&wrap_nonce), wrapped_key.as_slice())
+        .ok()
+        .filter(|key| key.len() == 32);
+    let Some(message_key) = message_key else { return Opened::Unreadable("wrong key".to_string()) };
+
+    let text = ChaCha20Poly1305::new(Key::from_slice(&message_key))
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &body, aad: envelope.from.as_bytes() })
+        .ok()
+        .and_then(|text| String::from_utf8(text).ok());
+    match text {
+        Some(text) => Opened::Sealed { chat: Chat { chat: text, user_id: envelope.from, user_name: envelope.name }, key: envelope.key },
+        None => Opened::Unreadable("tampered with".to_string()),
+    }
+}
+
+/// The chat as it reads, without its signature and opened when it is sealed for us, for exports
+pub fn reveal(identity: Option<&Identity>, me: &str, chat: &Chat) -> Chat {
+    let (body, _) = signing::split(&chat.chat);
+    match open(identity, me, body) {
+        Opened::Sealed { chat, .. } => chat,
+        Opened::Plain | Opened::Unreadable(_) => Chat { chat: body.to_string(), ..chat.clone() },
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{env, sync::Once};
+    use super::*;
+
+    /// The key pairs of the user `id`, kept under a MEOW_HOME of their own rather than the real one
+    pub(crate) fn identity(id: &str) -> Identity {
+        static HOME: Once = Once::new();
+        HOME.call_once(|| env::set_var("MEOW_HOME", env::temp_dir().join(format!("meow-e2e-test-{}", std::process::id()))));
+        Identity::load(id).unwrap()
+    }
+
+    #[test]
+    fn announcements_carry_both_keys() {
+        let alice = identity("a1");
+        let text = alice.announcement("a1");
+        assert!(announced_keys(signing::split(&text).0) == Some(alice.published()));
+        // Version 1 only had the encryption key
+        let old = announced_keys(&format!("meow-key:1:{}", alice.public_key())).unwrap();
+        assert!(old.seal == alice.public_key() && old.sign.is_none());
+        assert!(announced_keys("meow-key:2:not a key:x").is_none());
+        assert!(announced_keys("hello").is_none());
+    }
+
+    fn reason(opened: Opened) -> String {
+        match opened {
+            Opened::Unreadable(reason) => reason,
+            Opened::Plain => "plain".to_string(),
+            Opened::Sealed { chat, .. } => format!("opened: {}", chat.chat),
+        }
+    }
+
+    // Seals `text` from alice to bob, changes the envelope with `tamper` and tries to open it as bob
+    fn tampered(tamper: impl FnOnce(&mut Envelope)) -> String {
+        let (alice, bob) = (identity("a1"), identity("b2"));
+        let sealed = seal(&alice, "a1", "alice", "secret", &[("b2".to_string(), bob.public_key())]).unwrap();
+        let mut envelope: Envelope = serde_json::from_str(sealed.strip_prefix(SEAL_TAG).unwrap()).unwrap();
+        tamper(&mut envelope);
+        let text = format!("{}{}", SEAL_TAG, serde_json::to_string(&envelope).unwrap());
+        reason(open(Some(&bob), "b2", &text))
+    }
+
+    #[test]
+    fn sealed_chats_open_for_their_recipients_only() {
+        let (alice, bob, carol) = (identity("a1"), identity("b2"), identity("c3"));
+        let recipients = [("a1".to_string(), alice.public_key()), ("b2".to_string(), bob.public_key())];
+        let sealed = seal(&alice, "a1", "alice", "meet at noon", &recipients).unwrap();
+        assert!(!sealed.contains("noon"));
+
+        for (identity, id) in [(&alice, "a1"), (&bob, "b2")] {
+            let Opened::Sealed { chat, key } = open(Some(identity), id, &sealed) else { panic!("{} could not open it", id) };
+            assert_eq!((chat.chat.as_str(), chat.user_id.as_str(), chat.user_name.as_str()), ("meet at noon", "a1", "alice"));
+            assert_eq!(key, alice.public_key());
+        }
+        assert_eq!(reason(open(Some(&carol), "c3", &sealed)), "not sealed for us");
+        // Claiming to be bob does not give carol his key
+        assert_eq!(reason(open(Some(&carol), "b2", &sealed)), "wrong key");
+        assert_eq!(reason(open(None, "b2", &sealed)), "no key pair here");
+        assert_eq!(reason(open(Some(&bob), "b2", "just text")), "plain");
+        assert!(seal(&alice, "a1", "alice", "hi", &[("b2".to_string(), "broken".to_string())]).is_err());
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_open() {
+        assert_eq!(tampered(|_| {}), "opened: secret");
+        // The sender is bound to the ciphertext
+        assert_eq!(tampered(|envelope| envelope.from = "m9".to_string()), "tampered with");
+        assert_eq!(
+            tampered(|envelope| {
+                let mut body = BASE64.decode(&envelope.body).unwrap();
+                body[0] ^= 1;
+                envelope.body = BASE64.encode(body);
+            }),
+            "tampered with"
+        );
+        assert_eq!(tampered(|envelope| envelope.key = identity("m9").public_key()), "wrong key");
+        assert_eq!(tampered(|envelope| envelope.nonce = BASE64.encode([0u8; 5])), "damaged envelope");
+        assert_eq!(reason(open(Some(&identity("b2")), "b2", &format!("{}{{", SEAL_TAG))), "damaged envelope");
+    }
+
+    #[test]
+    fn reveal_strips_the_signature() {
+        let alice = identity("a1");
+        let signed = signing::sign(&alice, "a1", "b2", "hello");
+        let chat = Chat { chat: signed, user_id: "b2".to_string(), user_name: "bob".to_string() };
+        assert_eq!(reveal(Some(&alice), "a1", &chat).chat, "hello");
+    }
+}
@@ -0,0 +1,87 @@
+// src/events.rs
+
+use std::{sync::mpsc::Receiver, thread, time::Duration};
+use crossterm::event::{Event, EventStream};
+use futures::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::app::Done;
+use crate::request::Client;
+use crate::sync::SyncEvent;
+
+// How often the ticker fires, often enough for the busy spinner to look alive
+const TICK: Duration = Duration::from_millis(150);
+
+/// Everything the TUI reacts to, arriving on one channel
+pub enum AppEvent {
+    Input(Event),    // From the terminal
+    Sync(SyncEvent), // From the sync task
+    Done(Done),      // A call the network task made came back
+    Tick,
+}
+
+/// A call for the network task to make, turning into the event that says how it went
+pub type Job = Box<dyn FnOnce(&Client) -> Done + Send>;
+
+/// Reads the terminal without blocking anything else
+pub fn input(events: UnboundedSender<AppEvent>) {
+    tokio::spawn(async move {
+        let mut stream = EventStream::new();
+        while let Some(Ok(event)) = stream.next().await {
+            if events.send(AppEvent::Input(event)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Ticks for as long as someone listens
+pub fn ticks(events: UnboundedSender<AppEvent>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            if events.send(AppEvent::Tick).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Passes on what the sync task reports
+pub fn bridge(updates: Receiver<SyncEvent>, events: UnboundedSender<AppEvent>) {
+    thread::spawn(move || {
+        for update in updates {
+            if events.send(AppEvent::Sync(update)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Hands calls to the network task, which makes them one at a time in the order they came,
+/// so chats arrive in the order they were sent
+pub struct Network {
+    jobs: UnboundedSender<Job>,
+    pub client: Client,
+}
+
+impl Network {
+    pub fn start(client: Client, events: UnboundedSender<AppEvent>) -> Network {
+        let (jobs, mut queue) = unbounded_channel::<Job>();
+        let caller = client.clone();
+        // The client blocks, so the task runs where blocking is allowed. It ends with the Network.
+        tokio::task::spawn_blocking(move || {
+            while let Some(job) = queue.blocking_recv() {
+                if events.send(AppEvent::Done(job(&caller))).is_err() {
+                    return;
+                }
+            }
+        });
+        Network { jobs, client }
+    }
+
+    /// Queues a call, its outcome comes back as AppEvent::Done
+    pub fn run(&self, job: impl FnOnce(&Client) -> Done + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+}
@@ -0,0 +1,237 @@
+// src/export.rs
+
+use std::fmt::Write;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use crate::e2e::{announced_keys, reveal, Identity};
+use crate::history::Stamped;
+use crate::request::User;
+use crate::signing;
+
+/// Version of the JSON archive layout, bumped whenever a change would trip up older readers
+pub const ARCHIVE_VERSION: u32 = 1;
+
+pub const FORMATS: &str = "json|md|txt|html";
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Json,
+    Markdown,
+    Text,
+    Html,
+}
+
+impl Format {
+    pub fn parse(text: &str) -> Option<Format> {
+        match text {
+            "json" => Some(Format::Json),
+            "md" | "markdown" => Some(Format::Markdown),
+            "txt" | "text" => Some(Format::Text),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Markdown => "md",
+            Format::Text => "txt",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// The JSON archive. It reads like a `User` from the API with a version and export time on top,
+/// and each chat carries the time it was first seen, or null when that is not known.
+#[derive(Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    pub name: String,
+    pub id: String,
+    pub chats: Vec<Stamped>,
+}
+
+/// One conversation read back from a file
+pub struct Conversation {
+    pub id: String,
+    pub name: String,
+    pub chats: Vec<Stamped>,
+}
+
+/// Reads a JSON archive written by export, or what the Go API returns from `GET /user/`
+/// (or `GET /user/{id}`): users with their chats embedded and no times.
+pub fn read_archive(bytes: &[u8]) -> Result<Vec<Conversation>, String> {
+    let value: serde_json::Value = serde_json::from_slice(bytes).map_err(|error| format!("not JSON: {}", error))?;
+    let bad = |error: serde_json::Error| format!("not a meow archive or user list: {}", error);
+
+    let users: Vec<User> = match value {
+        serde_json::Value::Object(ref object) if object.contains_key("version") => {
+            // Check the version first, a newer layout may well fail to parse
+            let version = object["version"].as_u64().unwrap_or(0);
+            if version > ARCHIVE_VERSION as u64 {
+                return Err(format!("archive version {} is newer than this meow-cli understands ({})", version, ARCHIVE_VERSION));
+            }
+            let archive: Archive = serde_json::from_value(value).map_err(bad)?;
+            return Ok(vec![Conversation { id: archive.id, name: archive.name, chats: archive.chats }]);
+        }
+        serde_json::Value::Array(_) => serde_json::from_value(value).map_err(bad)?,
+        serde_json::Value::Null => Vec::new(), // The Go API's empty user list
+        _ => vec![serde_json::from_value(value).map_err(bad)?],
+    };
+
+    Ok(users
+        .into_iter()
+        .map(|user| Conversation {
+            chats: user.chat_log.into_iter().map(|chat| Stamped { chat, time: None }).collect(),
+            id: user.id,
+            name: user.name,
+        })
+        .collect())
+}
+
+// Times are shown in the local timezone, chats without one just leave it out
+fn time(stamped: &Stamped) -> Option<String> {
+    stamped.time.map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+}
+
+// Key announcements are plumbing, the readable formats leave them out
+fn readable(stamped: &Stamped) -> bool {
+    announced_keys(signing::split(&stamped.chat.chat).0).is_none()
+}
+
+// Backslashes whatever Markdown would take for formatting, and the start of a line that would begin
+// a heading, quote or list
+fn escape_markdown(text: &str) -> String {
+    let lines = text.split('\n').map(|line| {
+        let mut out = String::new();
+        for c in line.chars() {
+            if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|' | '~') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        let digits = out.len() - out.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if out.starts_with(['-', '+']) {
+            out.insert(0, '\\');
+        } else if digits > 0 && out[digits..].starts_with(['.', ')']) && out[digits + 1..].chars().next().is_none_or(char::is_whitespace) {
+            out.insert(digits, '\\');
+        }
+        out
+    });
+    lines.collect::<Vec<String>>().join("\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Renders the conversation with `id` in the given format. JSON keeps sealed chats as the server has
+/// them so the archive can be replayed, the other formats are for reading and open what they can.
+pub fn render(format: Format, id: &str, name: &str, chats: &[Stamped], identity: Option<&Identity>, me: &str) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Json => {
+            let archive = Archive { version: ARCHIVE_VERSION, exported: Utc::now(), name: name.to_string(), id: id.to_string(), chats: chats.to_vec() };
+            out = serde_json::to_string_pretty(&archive).unwrap_or_default();
+            out.push('\n');
+        }
+        Format::Text => {
+            let _ = writeln!(out, "Conversation with {} ({})\n", name, id);
+            for stamped in chats.iter().filter(|stamped| readable(stamped)) {
+                let chat = reveal(identity, me, &stamped.chat);
+                let text = chat.chat.replace('\n', "\n    ");
+                let time = time(stamped).map_or_else(String::new, |time| format!("[{}] ", time));
+                let _ = writeln!(out, "{}{}: {}", time, chat.user_name, text);
+            }
+        }
+        Format::Markdown => {
+            let _ = writeln!(out, "# Conversation with {}\n\n`{}`\n", escape_markdown(name), id);
+            for stamped in chats.iter().filter(|stamped| readable(stamped)) {
+                let chat = reveal(identity, me, &stamped.chat);
+                // Two trailing spaces keep the line breaks inside a message
+                let text = escape_markdown(&chat.chat).replace('\n', "  \n  ");
+                let time = time(stamped).map_or_else(String::new, |time| format!(" _{}_", time));
+                let _ = writeln!(out, "- **{}**{}: {}", escape_markdown(&chat.user_name), time, text);
+            }
+        }
+        Format::Html => {
+            let title = format!("Conversation with {}", escape_html(name));
+            let _ = writeln!(out, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>", title);
+            let _ = writeln!(out, "<h1>{}</h1>\n<p><code>{}</code></p>\n<ul>", title, escape_html(id));
+            for stamped in chats.iter().filter(|stamped| readable(stamped)) {
+                let chat = reveal(identity, me, &stamped.chat);
+                let time = time(stamped).map_or_else(String::new, |time| format!(" <time>{}</time>", time));
+                let text = escape_html(&chat.chat).replace('\n', "<br>");
+                let _ = writeln!(out, "<li><strong>{}</strong>{}: {}</li>", escape_html(&chat.user_name), time, text);
+            }
+            let _ = writeln!(out, "</ul>\n</body>\n</html>");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+    use crate::e2e::tests::identity;
+    use crate::request::Chat;
+
+    fn stamped(text: &str, from: &str, time: Option<i64>) -> Stamped {
+        let chat = Chat { chat: text.to_string(), user_id: from.to_string(), user_name: format!("{}'s name", from) };
+        Stamped { chat, time: time.map(|seconds| Utc.timestamp_opt(seconds, 0).unwrap()) }
+    }
+
+    #[test]
+    fn archives_read_back_what_was_written() {
+        let chats = [stamped("hello", "a1", Some(1_700_000_000)), stamped("two\nlines", "b2", None)];
+        let json = render(Format::Json, "b2", "bob", &chats, None, "a1");
+        let read = read_archive(json.as_bytes()).unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!((read[0].id.as_str(), read[0].name.as_str()), ("b2", "bob"));
+        let back: Vec<(&str, &str, Option<DateTime<Utc>>)> = read[0].chats.iter().map(|stamped| (stamped.chat.user_id.as_str(), stamped.chat.chat.as_str(), stamped.time)).collect();
+        let sent: Vec<(&str, &str, Option<DateTime<Utc>>)> = chats.iter().map(|stamped| (stamped.chat.user_id.as_str(), stamped.chat.chat.as_str(), stamped.time)).collect();
+        assert_eq!(back, sent);
+
+        // Newer archives are turned down rather than misread
+        let newer = json.replacen(&format!("\"version\": {}", ARCHIVE_VERSION), "\"version\": 99", 1);
+        assert!(read_archive(newer.as_bytes()).err().unwrap().contains("newer"));
+    }
+
+    #[test]
+    fn user_lists_from_the_api_read_as_conversations() {
+        let users = r#"[{"name": "alice", "id": "a1", "chats": null}, {"name": "bob", "id": "b2", "chats": [{"chat": "hi", "user": "a1", "name": "alice"}]}]"#;
+        let read = read_archive(users.as_bytes()).unwrap();
+        let counts: Vec<(&str, usize)> = read.iter().map(|conversation| (conversation.name.as_str(), conversation.chats.len())).collect();
+        assert_eq!(counts, [("alice", 0), ("bob", 1)]);
+        assert!(read[1].chats[0].time.is_none());
+        assert!(read_archive(b"null").unwrap().is_empty());
+        assert!(read_archive(b"{").is_err());
+    }
+
+    #[test]
+    fn markdown_keeps_message_text_as_written() {
+        let chats = [stamped("*not bold* and #1\n# not a heading\n- not a list\n2. nor this", "a1", None)];
+        let markdown = render(Format::Markdown, "a1", "al_ice", &chats, None, "a1");
+        assert!(markdown.starts_with("# Conversation with al\\_ice\n"));
+        assert!(markdown.contains("\\*not bold\\* and \\#1  \n  \\# not a heading  \n  \\- not a list  \n  2\\. nor this\n"));
+        assert_eq!(escape_markdown("1.30 [a](b) `code`"), "1.30 \\[a\\](b) \\`code\\`");
+    }
+
+    #[test]
+    fn readable_formats_open_sealed_chats_and_skip_announcements() {
+        let alice = identity("a1");
+        let sealed = crate::e2e::seal(&alice, "a1", "alice", "secret", &[("a1".to_string(), alice.public_key())]).unwrap();
+        let chats = [stamped(&alice.announcement("a1"), "a1", None), stamped(&sealed, "a1", None), stamped("<b>hi</b>", "a1", None)];
+
+        let text = render(Format::Text, "a1", "alice", &chats, Some(&alice), "a1");
+        assert_eq!(text, "Conversation with alice (a1)\n\nalice: secret\na1's name: <b>hi</b>\n");
+        let html = render(Format::Html, "a1", "alice", &chats, Some(&alice), "a1");
+        assert!(html.contains("&lt;b&gt;hi&lt;/b&gt;") && !html.contains("meow-key"));
+        // JSON keeps everything as the server had it
+        let json = render(Format::Json, "a1", "alice", &chats, Some(&alice), "a1");
+        assert_eq!(read_archive(json.as_bytes()).unwrap()[0].chats.len(), 3);
+    }
+}
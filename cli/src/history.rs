@@ -0,0 +1,215 @@
+// src/history.rs
+
+use std::{collections::HashMap, fs, io, path::PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::config::Profile;
+use crate::request::Chat;
+use crate::store::{data_dir, save_json};
+
+/// A chat and when this client first saw it. The server keeps no times, so chats
+/// that were already there the first time a conversation was fetched have none.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Stamped {
+    #[serde(flatten)]
+    pub chat: Chat,
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+}
+
+/// The local copy of one conversation
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Log {
+    pub name: String,
+    pub chats: Vec<Stamped>,
+    #[serde(default)]
+    synced: usize, // How many chats of the server log are already in `chats`
+    #[serde(default)]
+    reconcile: bool, // Whether chats coming from the server may be ones we already have
+    #[serde(default)]
+    pub shared: bool, // A room or DM thread, its chats come from more than us so they can not be replayed
+}
+
+/// Every chat seen so far, kept per server profile so they outlive the in-memory Go server
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    borrowed: bool, // The daemon owns the file while we are attached to it, our copy is never written back
+    #[serde(default)]
+    pub conversations: HashMap<String, Log>,
+}
+
+impl History {
+    /// Loads the history for a profile, starting empty when there is none yet or it can not be read
+    pub fn open(profile: &Profile) -> History {
+        let path = data_dir().join(format!("{}.history.json", profile.name));
+        let mut history: History = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        history.path = path;
+        history.borrowed = profile.attached;
+        history
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if self.borrowed {
+            return Ok(());
+        }
+        save_json(&self.path, self)
+    }
+
+    /// Marks a conversation as a room or DM thread, returns whether that changed anything
+    pub fn mark_shared(&mut self, id: &str) -> bool {
+        let Some(log) = self.conversations.get_mut(id) else { return false };
+        !std::mem::replace(&mut log.shared, true)
+    }
+
+    /// Adds the chats of a server log that are not recorded yet, returns whether anything changed
+    pub fn record(&mut self, id: &str, name: &str, chat_log: &[Chat]) -> bool {
+        let known = self.conversations.contains_key(id);
+        let log = self.conversations.entry(id.to_string()).or_default();
+        let renamed = log.name != name;
+        log.name = name.to_string();
+
+        // A shorter log means the server restarted and lost its chats, ours stay and recording starts over
+        if chat_log.len() < log.synced {
+            log.synced = 0;
+            log.reconcile = true;
+        }
+        let start = log.synced;
+        log.synced = chat_log.len();
+
+        let mut fresh: Vec<Stamped> = chat_log[start..].iter().map(|chat| Stamped { chat: chat.clone(), time: None }).collect();
+
+        // After a restart or an import the server may hand back chats we already have, until something new turns up
+        if log.reconcile {
+            fresh = self.missing(id, &fresh);
+            self.conversations.get_mut(id).unwrap().reconcile = fresh.is_empty();
+        }
+        let time = known.then(Utc::now);
+        for stamped in &mut fresh {
+            stamped.time = time;
+        }
+        let changed = renamed || !fresh.is_empty();
+        self.conversations.get_mut(id).unwrap().chats.extend(fresh);
+        changed
+    }
+
+    /// The chats that are not in the conversation with `id` yet. Chats carry no ids, so a chat counts
+    /// as already there when one with the same sender and text is, at the same time or with a time
+    /// missing on either side. Each local chat only stands in for one incoming chat, so a message
+    /// repeated on purpose is not lost.
+    pub fn missing(&self, id: &str, chats: &[Stamped]) -> Vec<Stamped> {
+        let local = self.conversations.get(id).map_or(&[][..], |log| log.chats.as_slice());
+        let mut used = vec![false; local.len()];
+
+        let mut missing = Vec::new();
+        for stamped in chats {
+            let same = local.iter().enumerate().position(|(index, known)| {
+                !used[index]
+                    && known.chat.user_id == stamped.chat.user_id
+                    && known.chat.chat == stamped.chat.chat
+                    && (known.time == stamped.time || known.time.is_none() || stamped.time.is_none())
+            });
+            match same {
+                Some(index) => used[index] = true,
+                None => missing.push(stamped.clone()),
+            }
+        }
+        missing
+    }
+
+    /// Appends chats from elsewhere to the conversation with `id`, they are not part of the server log
+    pub fn add(&mut self, id: &str, name: &str, chats: Vec<Stamped>) {
+        let log = self.conversations.entry(id.to_string()).or_default();
+        if log.name.is_empty() {
+            log.name = name.to_string();
+        }
+        log.chats.extend(chats);
+        log.reconcile = true;
+    }
+
+    /// The chats of the conversation with `id` that are not in the server's log for it
+    pub fn unsent(&self, id: &str, server: &[Chat]) -> Vec<Chat> {
+        let Some(log) = self.conversations.get(id) else { return Vec::new() };
+        let mut used = vec![false; server.len()];
+
+        let mut unsent = Vec::new();
+        for stamped in &log.chats {
+            let same = server.iter().enumerate().position(|(index, chat)| {
+                !used[index] && chat.user_id == stamped.chat.user_id && chat.chat == stamped.chat.chat
+            });
+            match same {
+                Some(index) => used[index] = true,
+                None => unsent.push(stamped.chat.clone()),
+            }
+        }
+        unsent
+    }
+
+    /// How many chats are kept locally, across every conversation
+    pub fn chat_count(&self) -> usize {
+        self.conversations.values().map(|log| log.chats.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    fn chat(text: &str, from: &str) -> Chat {
+        Chat { chat: text.to_string(), user_id: from.to_string(), user_name: from.to_string() }
+    }
+
+    fn stamped(text: &str, from: &str, time: Option<i64>) -> Stamped {
+        Stamped { chat: chat(text, from), time: time.map(|seconds| Utc.timestamp_opt(seconds, 0).unwrap()) }
+    }
+
+    fn texts(chats: &[Stamped]) -> Vec<&str> {
+        chats.iter().map(|stamped| stamped.chat.chat.as_str()).collect()
+    }
+
+    #[test]
+    fn missing_matches_each_chat_once() {
+        let mut history = History::default();
+        history.add("b2", "bob", vec![stamped("hi", "a1", Some(10)), stamped("hi", "a1", None), stamped("bye", "a1", Some(20))]);
+
+        let incoming = [
+            stamped("hi", "a1", Some(10)),
+            stamped("hi", "a1", Some(99)), // The chat without a time stands in for this one
+            stamped("hi", "a1", Some(50)), // But only once
+            stamped("bye", "a1", Some(21)),
+            stamped("bye", "b2", Some(20)),
+        ];
+        assert_eq!(texts(&history.missing("b2", &incoming)), ["hi", "bye", "bye"]);
+        assert_eq!(history.missing("nobody", &incoming).len(), incoming.len());
+    }
+
+    #[test]
+    fn unsent_is_what_the_server_lacks() {
+        let mut history = History::default();
+        history.add("b2", "bob", vec![stamped("one", "a1", None), stamped("two", "a1", None), stamped("one", "a1", None)]);
+
+        let unsent: Vec<String> = history.unsent("b2", &[chat("one", "a1"), chat("two", "b2")]).into_iter().map(|chat| chat.chat).collect();
+        assert_eq!(unsent, ["two", "one"]);
+        assert!(history.unsent("b2", &[chat("one", "a1"), chat("two", "a1"), chat("one", "a1")]).is_empty());
+        assert!(history.unsent("nobody", &[]).is_empty());
+    }
+
+    #[test]
+    fn a_shrinking_log_is_reconciled() {
+        let mut history = History::default();
+        assert!(history.record("b2", "bob", &[chat("one", "a1"), chat("two", "a1")]));
+        assert!(!history.record("b2", "bob", &[chat("one", "a1"), chat("two", "a1")]));
+
+        // The server restarted and got the first chat replayed, then a new one
+        assert!(!history.record("b2", "bob", &[chat("one", "a1")]));
+        assert!(history.record("b2", "bob", &[chat("one", "a1"), chat("three", "a1")]));
+        assert_eq!(texts(&history.conversations["b2"].chats), ["one", "two", "three"]);
+        assert_eq!(history.chat_count(), 3);
+    }
+}
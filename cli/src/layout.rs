@@ -0,0 +1,143 @@
+// src/layout.rs
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// Continuation lines fall back to this indent when the sender prefix would eat most of the row
+const FALLBACK_INDENT: usize = 2;
+
+/// Width of a string in terminal columns
+pub fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(text)
+}
+
+/// Cuts text down to at most `width` columns, ending with an ellipsis when something was dropped
+pub fn truncate(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+
+    let mut cut = String::new();
+    let mut cut_width = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if cut_width + char_width + 1 > width {
+            break;
+        }
+        cut.push(c);
+        cut_width += char_width;
+    }
+    if width > 0 {
+        cut.push('\u{2026}');
+    }
+    cut
+}
+
+// Wraps text on word boundaries so that the first row is at most `first_width` columns and the rest
+// at most `rest_width`. Hard line breaks are kept and words wider than a whole row are split between characters.
+fn wrap_hanging(text: &str, first_width: usize, rest_width: usize) -> Vec<String> {
+    let mut rows: Vec<String> = Vec::new();
+    let limit = |rows: &Vec<String>| if rows.is_empty() { first_width } else { rest_width }.max(1);
+
+    for paragraph in text.split('\n') {
+        let mut row = String::new();
+        let mut row_width = 0;
+
+        for word in paragraph.split_whitespace() {
+            let word_width = display_width(word);
+
+            // Start a new row when the word does not fit after a space
+            if row_width > 0 && row_width + 1 + word_width > limit(&rows) {
+                rows.push(std::mem::take(&mut row));
+                row_width = 0;
+            }
+            if row_width > 0 {
+                row.push(' ');
+                row_width += 1;
+            }
+
+            if row_width + word_width <= limit(&rows) {
+                row.push_str(word);
+                row_width += word_width;
+                continue;
+            }
+
+            // The word is wider than a whole row, so break it wherever it runs out of room
+            for c in word.chars() {
+                let char_width = c.width().unwrap_or(0);
+                if row_width > 0 && row_width + char_width > limit(&rows) {
+                    rows.push(std::mem::take(&mut row));
+                    row_width = 0;
+                }
+                row.push(c);
+                row_width += char_width;
+            }
+        }
+
+        rows.push(row);
+    }
+
+    rows
+}
+
+/// Columns that continuation lines are indented by so they sit under the message body
+pub fn hanging_indent(prefix: &str, width: usize) -> usize {
+    let indent = display_width(prefix);
+    if indent * 2 > width { FALLBACK_INDENT } else { indent }
+}
+
+/// Lays out a message for the history pane. The first row goes right after `prefix`
+/// and every other row goes after `hanging_indent(prefix, width)` spaces.
+pub fn layout_message(prefix: &str, text: &str, width: usize) -> Vec<String> {
+    let first = width.saturating_sub(display_width(prefix));
+    let rest = width.saturating_sub(hanging_indent(prefix, width));
+    // A prefix that fills the whole row leaves the text to start on the next one
+    if first == 0 {
+        return std::iter::once(String::new()).chain(wrap_hanging(text, rest, rest)).collect();
+    }
+    wrap_hanging(text, first, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_ends_in_an_ellipsis() {
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("hello world", 6), "hello\u{2026}");
+        // Wide characters count two columns each and are never cut in half
+        assert_eq!(truncate("日本語", 4), "日\u{2026}");
+        assert_eq!(truncate("hello", 1), "\u{2026}");
+        assert_eq!(truncate("hello", 0), "");
+    }
+
+    #[test]
+    fn wrapping_keeps_words_and_line_breaks() {
+        assert_eq!(wrap_hanging("one two three", 7, 5), ["one two", "three"]);
+        assert_eq!(wrap_hanging("one\ntwo", 10, 10), ["one", "two"]);
+        // A word wider than a row is split wherever it runs out of room
+        assert_eq!(wrap_hanging("abcdefgh", 3, 4), ["abc", "defg", "h"]);
+        assert_eq!(wrap_hanging("", 5, 5), [""]);
+    }
+
+    #[test]
+    fn messages_hang_under_their_body() {
+        assert_eq!(hanging_indent("bob: ", 40), 5);
+        // A prefix taking more than half the row would leave too little room under it
+        assert_eq!(hanging_indent("a long sender name: ", 30), FALLBACK_INDENT);
+
+        let rows = layout_message("bob: ", "the quick brown fox", 15);
+        assert_eq!(rows, ["the quick", "brown fox"]);
+        assert!(rows.iter().all(|row| display_width(row) + 5 <= 15));
+    }
+
+    #[test]
+    fn a_prefix_wider_than_the_row_moves_the_text_down() {
+        let prefix = "somebody with a long name: ";
+        let rows = layout_message(prefix, "hi there", 10);
+        assert_eq!(rows, ["", "hi there"]);
+        for row in &rows[1..] {
+            assert!(hanging_indent(prefix, 10) + display_width(row) <= 10);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// src/lib.rs
+
+// The client side of the chat API: which server to talk to and how, logging in, and the calls
+// themselves. The TUI is built on it, and meow-server's tests talk to it through it.
+pub mod auth;
+pub mod config;
+pub mod notify;
+pub mod proxy;
+pub mod request;
+pub mod store;
+pub mod tls;
+pub mod transport;
+
+use std::process::Command;
+use mac_address::get_mac_address;
+use meow_common::{api, memory};
+use sha2::{Digest, Sha256};
+
+fn get_computer_hash() -> String {
+    // Get the MAC address
+    let mac = get_mac_address()
+        .expect("Failed to get MAC address")
+        .expect("No MAC address found");
+
+    // Get the hostname
+    let hostname = Command::new("hostname")
+        .output()
+        .expect("Failed to get hostname")
+        .stdout;
+
+    let hostname = String::from_utf8_lossy(&hostname).trim().to_string();
+
+    // Combine MAC and hostname
+    let identifier = format!("{}-{}", mac, hostname);
+    
+    // Generate a SHA256 hash
+    let mut hasher = Sha256::new();
+    hasher.update(identifier.as_bytes());
+    let result = hasher.finalize();
+
+    // Convert the hash to hex format
+    format!("{:x}", result)
+}
+
+pub fn generate_id() -> String {get_computer_hash()}
@@ -1,62 +1,154 @@
-use std::{time::Duration, io::{self, Write}};
+use std::{env, io};
 use crossterm::{
     execute,
     terminal::{enable_raw_mode, disable_raw_mode, Clear, ClearType, size},
-    event::{poll, read, Event, KeyCode, KeyModifiers, KeyEvent},
-    cursor, style::{Print, SetForegroundColor, Color},
+    event::{
+        Event, KeyCode, KeyModifiers, KeyEvent,
+        EnableBracketedPaste, DisableBracketedPaste, EnableFocusChange, DisableFocusChange,
+    },
+    cursor, style::Print,
 };
-use std::process::Command;
-use sha2::{Sha256, Digest};
-use mac_address::get_mac_address;
 
-mod request; 
-use request::{add_user, get_user_by_id};
+mod layout;
+mod theme;
+mod status;
+mod sync;
+mod push;
+#[cfg(unix)]
+mod daemon;
+mod mention;
+mod picker;
+mod search;
+mod history;
+mod export;
+mod cli;
+mod e2e;
+mod signing;
+mod app;
+mod events;
+use meow_cli::{config, generate_id, notify, request, store};
+use meow_common::{api, serve};
+use request::{add_user, Client};
+use theme::{Theme, THEME_NAMES};
+use config::Profile;
+use app::{App, Asked, Confirmed};
+use events::{AppEvent, Network};
+use notify::{Level, QuietHours};
 
-fn get_computer_hash() -> String {
-    // Get the MAC address
-    let mac = get_mac_address()
-        .expect("Failed to get MAC address")
-        .expect("No MAC address found");
+// Pastes larger than this many characters ask before landing in the composer
+const PASTE_CONFIRM_LIMIT: usize = 2048;
 
-    // Get the hostname
-    let hostname = Command::new("hostname")
-        .output()
-        .expect("Failed to get hostname")
-        .stdout;
+// Handles a line starting with '/' typed into the composer
+fn run_command(app: &mut App, line: &str) -> io::Result<()> {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
 
-    let hostname = String::from_utf8_lossy(&hostname).trim().to_string();
+    match command {
+        "/theme" => {
+            // Switch themes on the fly and redraw everything in the new colors
+            match Theme::named(argument) {
+                Some(new_theme) => {
+                    app.theme = new_theme;
+                    app.draw()?;
+                    app.notice(format!("Theme set to {}", app.theme.name))
+                }
+                None => app.error(format!("Unknown theme '{}', try one of: {}", argument, THEME_NAMES.join(", "))),
+            }
+        }
+        // Jump to the first message we have not seen yet
+        "/unread" => app.jump_to_unread(),
+        // List every message that mentioned us
+        "/mentions" => app.show_mentions(),
+        // Write the conversation to a file
+        "/export" => app.export(argument),
+        // End-to-end encryption for the active conversation
+        "/encrypt" => match argument {
+            "on" => app.set_encrypted(true),
+            "off" => app.set_encrypted(false),
+            _ => app.error("Usage: /encrypt on|off".to_string()),
+        },
+        "/trust" => app.trust(),
+        // Get a new token from the server
+        "/login" => app.login(argument),
+        // Put the local history back after the server lost it
+        "/replay" => app.replay(),
+        // Rooms: every member gets what is posted there
+        "/rooms" => app.list_rooms(),
+        "/create" => app.create_room(argument),
+        "/join" => app.join_room(argument),
+        "/leave" => app.leave_room(argument),
+        // Find old messages, the results open in a picker
+        "/search" => app.search(argument),
+        // Notification controls
+        "/mute" => app.set_muted(true),
+        "/unmute" => app.set_muted(false),
+        "/notify" => match Level::parse(argument) {
+            Some(level) => app.set_notify_level(level),
+            None => app.error("Usage: /notify all|mentions|off".to_string()),
+        },
+        "/dnd" if argument == "off" => app.set_quiet_hours(None),
+        "/dnd" => match QuietHours::parse(argument) {
+            Some(quiet) => app.set_quiet_hours(Some(quiet)),
+            None => app.error("Usage: /dnd HH:MM-HH:MM or /dnd off".to_string()),
+        },
+        _ => app.error(format!("Unknown command {}", command)),
+    }
+}
 
-    // Combine MAC and hostname
-    let identifier = format!("{}-{}", mac, hostname);
-    
-    // Generate a SHA256 hash
-    let mut hasher = Sha256::new();
-    hasher.update(identifier.as_bytes());
-    let result = hasher.finalize();
+fn main() -> io::Result<()> {
+    // Subcommands like export run on their own, without the TUI
+    let mut args: Vec<String> = env::args().skip(1).collect();
 
-    // Convert the hash to hex format
-    format!("{:x}", result)
-}
+    // --insecure goes for the TUI and every subcommand alike, it is MEOW_INSECURE=1 by another name
+    if args.iter().any(|arg| arg == "--insecure") {
+        args.retain(|arg| arg != "--insecure");
+        env::set_var("MEOW_INSECURE", "1");
+    }
+    let mut profile = Profile::from_env(); // Which server to talk to
+
+    // attach is the TUI as usual, only talking to the daemon instead of the server
+    if args.first().is_some_and(|arg| arg == "attach") {
+        #[cfg(unix)]
+        let attached = daemon::attach(&mut profile);
+        #[cfg(not(unix))]
+        let attached: Result<(), String> = Err("attach needs Unix sockets".to_string());
+        if let Err(error) = attached {
+            eprintln!("meow-cli: {}", error);
+            std::process::exit(1);
+        }
+    } else if !args.is_empty() {
+        if let Err(error) = cli::run(&args) {
+            eprintln!("meow-cli: {}", error);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-fn generate_id() -> String {get_computer_hash()}
+    // The TUI runs on an async runtime, so reading keys, talking to the server and redrawing never wait on each other
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let result = runtime.block_on(tui(profile));
+    // The network task may be in the middle of a call, it is not waited for
+    runtime.shutdown_background();
+    result
+}
 
+async fn tui(profile: Profile) -> io::Result<()> {
+    // Keys, sync updates, finished calls and ticks all arrive here
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    events::input(sender.clone());
+    events::ticks(sender.clone());
 
-fn main() -> io::Result<()> {
-    let hash = get_computer_hash();
     enable_raw_mode().unwrap(); // Enable raw mode to capture key presses directly
 
     let (cols, rows) = size()?; // Get terminal size
+    let (theme, theme_error) = Theme::from_env(); // Pick colors, honouring NO_COLOR
     let mut stdout = io::stdout(); // Use stdout for output
 
-    // Clear the screen
-    execute!(stdout, Clear(ClearType::All))?;
+    // Clear the screen, have pastes delivered as a single event and hear when the window loses focus
+    execute!(stdout, Clear(ClearType::All), EnableBracketedPaste, EnableFocusChange)?;
 
-    // Draw the input box
-    let input_line = rows - 2; // The line for input
-    execute!(stdout, Print("+".to_string() + &"-".repeat((cols - 3) as usize) + "+\n"))?; // Top of the box
-    execute!(stdout, Print("|".to_string() + &" ".repeat((cols - 3) as usize) + "|\n"))?; // Empty line
-    
     // Move cursor to the input line
+    let input_line = rows - 2; // The line for input
     execute!(stdout, cursor::MoveTo(1, input_line))?;
 
     // Get username input
@@ -64,157 +156,166 @@ fn main() -> io::Result<()> {
     execute!(stdout, Print("Enter your username: "))?;
     
     // Read characters for the username input
-    loop {
-        if poll(Duration::from_millis(500))? {
-            match read()? {
+    while let Some(event) = events.recv().await {
+        if let AppEvent::Input(event) = event {
+            match event {
                 Event::Key(KeyEvent { code: KeyCode::Enter, .. }) => {
                     break; // Break on Enter
                 }
-                Event::Key(KeyEvent { code: KeyCode::Backspace, .. }) => {
-                    if !username.is_empty() {
-                        username.pop(); // Remove the last character
-                        execute!(stdout, cursor::MoveTo(1, input_line), Clear(ClearType::CurrentLine))?;
-                        execute!(stdout, Print(format!("Enter your username: {}", username)))?; // Print the updated username
-                    }
+                Event::Key(KeyEvent { code: KeyCode::Backspace, .. }) if !username.is_empty() => {
+                    username.pop(); // Remove the last character
+                    execute!(stdout, cursor::MoveTo(1, input_line), Clear(ClearType::CurrentLine))?;
+                    execute!(stdout, Print(format!("Enter your username: {}", username)))?; // Print the updated username
                 }
                 Event::Key(KeyEvent { code: KeyCode::Char(c), .. }) => {
                     username.push(c); // Append the character to the username
                     execute!(stdout, Print(c))?; // Print the character
                 }
+                Event::Paste(text) => {
+                    // Names are a single line, so drop any pasted line breaks
+                    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+                    username.push_str(&text);
+                    execute!(stdout, Print(text))?;
+                }
                 _ => {}
             }
         }
     }
 
-    // Clear the input line
-    execute!(stdout, cursor::MoveTo(0, input_line + 1), Clear(ClearType::CurrentLine))?;
-    
-    // Print the entered username and hash in the top right corner
-    execute!(stdout, cursor::MoveTo(cols - 30, 0), Print(format!("Username: {}", username)))?;
+    // Register with the server in the background and start syncing our own chat log
+    let me = add_user(username);
+    let client = Client::new(&profile);
+    let (sync, updates) = sync::spawn(client.clone(), me.clone());
+    events::bridge(updates, sender.clone());
 
-    add_user(username);
-    
-    // Main loop for input handling
-    let mut message = String::new(); // Store current input message
-    let mut message_line = 0; // Track the message line to print above the input box
-
-    loop {
-        // Poll for events every 500 ms
-        if poll(Duration::from_millis(500))? {
-            match read()? {
-                // Handle key events
-                Event::Key(key_event) => {
-                    match key_event {
-                        // Exit on Ctrl+Q
-                        KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL, .. } => {
-                            break;
-                        }
-                        KeyEvent { code: KeyCode::Char('m'), modifiers: KeyModifiers::CONTROL, .. } => {
-                            break;
-                        }
-                        KeyEvent { code: KeyCode::Char('w'), modifiers: KeyModifiers::CONTROL, .. } => {
-                            // Clear the input line and prompt for ID
-                            execute!(stdout, Clear(ClearType::CurrentLine))?;
-                            execute!(stdout, cursor::MoveTo(1, input_line), Print("What ID? "))?;
-
-                            let mut id_input = String::new(); // To store the user's input for the ID
-                            // Read characters for the ID input
-                            loop {
-                                if poll(Duration::from_millis(500))? {
-                                    match read()? {
-                                        Event::Key(KeyEvent { code, modifiers, .. }) => {
-                                            match (code, modifiers) {
-                                                (KeyCode::Enter, _) => {
-                                                    break; // Break on Enter
-                                                }
-                                                (KeyCode::Backspace, _) => {
-                                                    if !id_input.is_empty() {
-                                                        id_input.pop(); // Remove the last character
-                                                        // Clear the ID input line
-                                                        execute!(stdout, cursor::MoveTo(1, input_line), Clear(ClearType::CurrentLine))?;
-                                                        execute!(stdout, cursor::MoveTo(1, input_line), Print("What ID? "))?; // Reprint the prompt
-                                                        execute!(stdout, Print(&id_input))?; // Print the updated ID
-                                                        // Move cursor to the end of the ID input
-                                                        execute!(stdout, cursor::MoveTo((id_input.len() + 1) as u16, input_line))?;
-                                                    }
-                                                }
-                                                (KeyCode::Char(c), _) => {
-                                                    id_input.push(c); // Append the character to the ID input
-                                                    execute!(stdout, Print(c))?; // Print the character
-                                                }
-                                                _ => {}
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-
-                            // After getting the ID, you can do something with it, like print it
-                            execute!(stdout, cursor::MoveTo(0, input_line + 1), Clear(ClearType::CurrentLine))?; // Clear the line below the input
-                            execute!(stdout, Print(format!("You entered ID: {}", id_input)))?; // Print the entered ID
-                        }
-                        // Handle Enter key: send/print the message
-                        KeyEvent { code: KeyCode::Enter, .. } => {
-                            // If the message line is at the top, we can scroll down
-                            if message_line < input_line - 2 {
-                                message_line += 1; // Move to the next line for the next message
-                            } else {
-                                // Clear the screen and redraw the box and messages
-                                execute!(stdout, Clear(ClearType::All), cursor::MoveTo(0, 0))?;
-                                execute!(stdout, Print("+".to_string() + &"-".repeat((cols - 2) as usize) + "+\n"))?;
-                                execute!(stdout, Print("|".to_string() + &" ".repeat((cols - 2) as usize) + "|\n"))?;
-                                execute!(stdout, Print("+".to_string() + &"-".repeat((cols - 2) as usize) + "+\n"))?;
-                                
-                                // Reset message line position to allow for new messages
-                                message_line = 0;
-                            }
-
-                            // Move to the next line to print the message
-                            execute!(stdout, cursor::MoveTo(1, message_line), Print(&message))?;
-
-                            // Clear the message input line
-                            message.clear(); // Clear the message in memory
-                            
-                            // Move back to the input line
-                            execute!(
-                                stdout,
-                                cursor::MoveTo(1, input_line),         // Move cursor to input line
-                                Clear(ClearType::CurrentLine)         // Clear the current line
-                            )?;
-                        }
-                        // Handle regular character input
-                        KeyEvent { code: KeyCode::Char(c), .. } => {
-                            // Append the character to the message string
-                            message.push(c);
-                            // Print the character on the screen
-                            execute!(stdout, Print(c))?;
-                        }
-                        // Handle Backspace key
-                        KeyEvent { code: KeyCode::Backspace, .. } => {
-                            if !message.is_empty() {
-                                message.pop(); // Remove the last character from the message
-                            }
+    let mut app = App::new(me, &profile, Network::start(client, sender), theme, cols, rows, sync);
+    app.draw()?;
 
-                            // Clear the area of the input box
-                            execute!(stdout, cursor::MoveTo(1, input_line), Clear(ClearType::CurrentLine))?;
+    // Report a bad MEOW_THEME in the history pane rather than failing
+    if let Some(error) = theme_error {
+        app.error(error)?;
+    }
+    if profile.insecure {
+        app.error("Server certificates are not checked (--insecure)".to_string())?;
+    }
 
-                            // Print the updated message
-                            execute!(stdout, Print(&message))?;
+    // Main loop: handle whatever comes next, none of it waits on the server
+    while let Some(event) = events.recv().await {
+        let input = match event {
+            AppEvent::Input(input) => input,
+            AppEvent::Sync(update) => {
+                app.handle_sync(update)?;
+                continue;
+            }
+            AppEvent::Done(done) => {
+                app.finish(done)?;
+                continue;
+            }
+            AppEvent::Tick => {
+                app.tick()?;
+                continue;
+            }
+        };
+        match input {
+            // While a prompt or question is up it takes the keys
+            Event::Key(_) | Event::Paste(_) if app.asking() => app.answer(input)?,
+            // While a picker is open the arrow keys, Enter and Esc drive it
+            Event::Key(KeyEvent { code: KeyCode::Up, .. }) if app.picker.is_some() => app.picker_step(-1)?,
+            Event::Key(KeyEvent { code: KeyCode::Down, .. }) if app.picker.is_some() => app.picker_step(1)?,
+            Event::Key(KeyEvent { code: KeyCode::Enter, .. }) if app.picker.is_some() => app.picker_choose()?,
+            Event::Key(KeyEvent { code: KeyCode::Esc, .. }) if app.picker.is_some() => app.close_picker()?,
+            // Handle key events
+            Event::Key(key_event) => {
+                match key_event {
+                    // Exit on Ctrl+Q
+                    KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        break;
+                    }
+                    KeyEvent { code: KeyCode::Char('m'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        break;
+                    }
+                    KeyEvent { code: KeyCode::Char('w'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        // Ask which conversation to open, Esc goes back to the composer
+                        app.ask("What ID? ", false, Asked::Switch)?;
+                    }
+                    // Ask for a search and show the results over the history pane
+                    KeyEvent { code: KeyCode::Char('f'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        app.ask("Search: ", false, Asked::Search)?;
+                    }
+                    // Cycle through the buffer list
+                    KeyEvent { code: KeyCode::Char('n'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        app.cycle(false)?;
+                    }
+                    KeyEvent { code: KeyCode::Char('p'), modifiers: KeyModifiers::CONTROL, .. } => {
+                        app.cycle(true)?;
+                    }
+                    // Scroll the history pane half a screen at a time
+                    KeyEvent { code: KeyCode::PageUp, .. } => {
+                        app.scroll(app.input_line as isize / 2)?;
+                    }
+                    KeyEvent { code: KeyCode::PageDown, .. } => {
+                        app.scroll(-(app.input_line as isize / 2))?;
+                    }
+                    // Complete the @name being typed
+                    KeyEvent { code: KeyCode::Tab, .. } => {
+                        app.complete_mention()?;
+                    }
+                    // Handle Enter key: send/print the message
+                    KeyEvent { code: KeyCode::Enter, .. } => {
+                        let message = std::mem::take(&mut app.message); // Clear the message in memory
 
-                            // Move the cursor back to the end of the input line
-                            execute!(stdout, cursor::MoveTo((message.len() + 1) as u16, input_line))?;
+                        if message.starts_with('/') {
+                            run_command(&mut app, message.trim())?;
+                        } else if !message.trim().is_empty() {
+                            // Post the message to the active conversation
+                            app.send(message)?;
                         }
-                        _ => {} // Handle other keys that are not defined
+
+                        // Clear the message input line
+                        app.draw_input()?;
+                    }
+                    // Handle regular character input
+                    KeyEvent { code: KeyCode::Char(c), .. } => {
+                        // Append the character to the message string
+                        app.message.push(c);
+                        // Print the character on the screen
+                        app.draw_input()?;
+                    }
+                    // Handle Backspace key
+                    KeyEvent { code: KeyCode::Backspace, .. } => {
+                        app.message.pop(); // Remove the last character from the message
+
+                        // Print the updated message, the cursor is left at its end
+                        app.draw_input()?;
                     }
+                    _ => {} // Handle other keys that are not defined
                 }
-                _ => {}
             }
+            // Handle a bracketed paste: the whole block lands in the composer at once
+            Event::Paste(text) => {
+                let text = text.replace("\r\n", "\n").replace('\r', "\n");
+                let length = text.chars().count();
+
+                if length > PASTE_CONFIRM_LIMIT {
+                    app.confirm(format!("Paste {} characters?", length), Confirmed::Paste(text))?;
+                } else {
+                    app.message.push_str(&text);
+                    app.draw_input()?;
+                }
+            }
+            // Notifications also fire for the open conversation while the window is in the background
+            Event::FocusLost => app.set_focus(false)?,
+            Event::FocusGained => app.set_focus(true)?,
+            // Reflow everything to the new terminal size
+            Event::Resize(new_cols, new_rows) => {
+                app.resize(new_cols, new_rows)?;
+            }
+            _ => {}
         }
     }
 
-    // Disable raw mode before exiting
+    // Disable bracketed paste, focus reports and raw mode before exiting
+    execute!(app.stdout, DisableBracketedPaste, DisableFocusChange)?;
     disable_raw_mode()?;
     Ok(())
 }
-
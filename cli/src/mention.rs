@@ -0,0 +1,117 @@
+// src/mention.rs
+
+// Characters that end an @name token besides whitespace
+fn is_boundary(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ',' | '.' | ':' | ';' | '!' | '?' | '(' | ')' | '"' | '\'')
+}
+
+/// True when `text` contains @name as a whole word, ignoring case
+pub fn mentions(text: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let name = name.to_lowercase();
+    text.split(is_boundary)
+        .filter_map(|word| word.strip_prefix('@'))
+        .any(|word| word.to_lowercase() == name)
+}
+
+/// Tab completion state for an @name at the end of the composer
+pub struct Completion {
+    start: usize, // Byte offset of the '@' in the composer
+    candidates: Vec<String>,
+    index: usize,
+}
+
+impl Completion {
+    fn current(&self) -> String {
+        format!("@{}", self.candidates[self.index])
+    }
+}
+
+/// Completes the @name being typed at the end of `message` against `names`.
+/// Pressing Tab again right after a completion moves on to the next matching name.
+pub fn complete(message: &mut String, names: &[String], completion: &mut Option<Completion>) -> bool {
+    // Still sitting on our last completion, so cycle to the next candidate
+    if let Some(state) = completion.as_mut() {
+        if message.get(state.start..) == Some(state.current().as_str()) {
+            state.index = (state.index + 1) % state.candidates.len();
+            message.truncate(state.start);
+            message.push_str(&state.current());
+            return true;
+        }
+    }
+
+    let start = message.rfind(is_boundary).map_or(0, |boundary| boundary + message[boundary..].chars().next().unwrap().len_utf8());
+    let Some(prefix) = message[start..].strip_prefix('@') else {
+        *completion = None;
+        return false;
+    };
+
+    let prefix = prefix.to_lowercase();
+    let mut candidates: Vec<String> = names
+        .iter()
+        .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace) && name.to_lowercase().starts_with(&prefix))
+        .cloned()
+        .collect();
+    candidates.sort_by_key(|name| name.to_lowercase());
+    candidates.dedup();
+
+    if candidates.is_empty() {
+        *completion = None;
+        return false;
+    }
+
+    let state = Completion { start, candidates, index: 0 };
+    message.truncate(start);
+    message.push_str(&state.current());
+    *completion = Some(state);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        ["alice", "Albert", "bob", "al smith", ""].iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn mentions_are_whole_words() {
+        assert!(mentions("hey @Alice, look", "alice"));
+        assert!(mentions("(@bob)", "bob"));
+        assert!(!mentions("hey @alicex", "alice"));
+        assert!(!mentions("mail alice@example.com", "alice"));
+        assert!(!mentions("hey @", ""));
+    }
+
+    #[test]
+    fn tab_cycles_through_matching_names() {
+        let mut message = "hi @al".to_string();
+        let mut completion = None;
+        // Names with spaces can not be typed after an @, and the list is sorted ignoring case
+        assert!(complete(&mut message, &names(), &mut completion));
+        assert_eq!(message, "hi @Albert");
+        assert!(complete(&mut message, &names(), &mut completion));
+        assert_eq!(message, "hi @alice");
+        assert!(complete(&mut message, &names(), &mut completion));
+        assert_eq!(message, "hi @Albert");
+
+        // Typing something else starts over
+        message.push_str(", @b");
+        assert!(complete(&mut message, &names(), &mut completion));
+        assert_eq!(message, "hi @Albert, @bob");
+    }
+
+    #[test]
+    fn nothing_to_complete() {
+        let mut completion = None;
+        let mut message = "hi al".to_string();
+        assert!(!complete(&mut message, &names(), &mut completion));
+        let mut message = "hi @zed".to_string();
+        assert!(!complete(&mut message, &names(), &mut completion));
+        assert_eq!(message, "hi @zed");
+        assert!(completion.is_none());
+    }
+}
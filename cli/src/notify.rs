@@ -0,0 +1,215 @@
+// src/notify.rs
+
+use std::{env, fmt, io::{self, Write}, process::{Command, Stdio}, thread};
+use chrono::{Local, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+/// Which incoming messages raise a notification
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    #[default]
+    All,
+    Mentions,
+    Off,
+}
+
+impl Level {
+    pub fn parse(text: &str) -> Option<Level> {
+        match text {
+            "all" => Some(Level::All),
+            "mentions" => Some(Level::Mentions),
+            "off" => Some(Level::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A daily do-not-disturb window in minutes after midnight, it may wrap past midnight
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl QuietHours {
+    /// Parses a window like "22:00-07:30"
+    pub fn parse(text: &str) -> Option<QuietHours> {
+        let (start, end) = text.split_once('-')?;
+        let minutes = |time: &str| {
+            let time = NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()?;
+            Some((time.hour() * 60 + time.minute()) as u16)
+        };
+        Some(QuietHours { start: minutes(start)?, end: minutes(end)? })
+    }
+
+    fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+/// Notification settings changed from the TUI and kept in the store
+#[derive(Default, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub level: Level,
+    #[serde(default)]
+    pub muted: Vec<String>,
+    #[serde(default)]
+    pub quiet: Option<QuietHours>,
+}
+
+impl Settings {
+    /// Whether a message in `conversation` should notify, `mentioned` if it names us.
+    /// Mentions get through a muted conversation, but not quiet hours.
+    pub fn allows(&self, conversation: &str, mentioned: bool) -> bool {
+        let now = Local::now();
+        self.allows_at(conversation, mentioned, (now.hour() * 60 + now.minute()) as u16)
+    }
+
+    // The same at `minute` after midnight local time
+    fn allows_at(&self, conversation: &str, mentioned: bool, minute: u16) -> bool {
+        if !mentioned && self.muted.iter().any(|id| id == conversation) {
+            return false;
+        }
+        if self.quiet.is_some_and(|quiet| quiet.contains(minute)) {
+            return false;
+        }
+        match self.level {
+            Level::All => true,
+            Level::Mentions => mentioned,
+            Level::Off => false,
+        }
+    }
+}
+
+// Which desktop notification escape the terminal understands
+enum Osc {
+    Off,
+    Nine,             // iTerm2, Windows Terminal, WezTerm
+    SevenSevenSeven,  // urxvt, foot, Ghostty, VTE based terminals
+}
+
+/// Delivers notifications, configured with MEOW_BELL, MEOW_NOTIFY_OSC and MEOW_NOTIFY_COMMAND
+pub struct Notifier {
+    bell: bool,
+    osc: Osc,
+    command: Option<String>,
+}
+
+// Escapes end at BEL or ESC, and OSC 777 splits its fields on ';'
+fn clean(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).map(|c| if c == ';' { ',' } else { c }).collect()
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        let osc = match env::var("MEOW_NOTIFY_OSC").as_deref() {
+            Ok("9") => Osc::Nine,
+            Ok("777") => Osc::SevenSevenSeven,
+            _ => Osc::Off,
+        };
+        Notifier {
+            bell: env::var("MEOW_BELL").map_or(true, |value| value != "0"),
+            osc,
+            command: env::var("MEOW_NOTIFY_COMMAND").ok().filter(|command| !command.trim().is_empty()),
+        }
+    }
+
+    /// Rings the bell, sends the desktop escape and runs the hook, whichever are turned on
+    pub fn notify(&self, out: &mut impl Write, title: &str, body: &str) -> io::Result<()> {
+        if self.bell {
+            write!(out, "\x07")?;
+        }
+        match self.osc {
+            Osc::Off => {}
+            Osc::Nine => write!(out, "\x1b]9;{}: {}\x07", clean(title), clean(body))?,
+            Osc::SevenSevenSeven => write!(out, "\x1b]777;notify;{};{}\x07", clean(title), clean(body))?,
+        }
+        out.flush()?;
+
+        // The hook gets the details in its environment and runs on its own thread so a slow one never blocks typing
+        if let Some(command) = self.command.clone() {
+            let (title, body) = (title.to_string(), body.to_string());
+            thread::spawn(move || {
+                let _ = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("MEOW_TITLE", title)
+                    .env("MEOW_MESSAGE", body)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_hours_parse_and_print() {
+        let quiet = QuietHours::parse("22:00 - 07:30").unwrap();
+        assert_eq!((quiet.start, quiet.end), (22 * 60, 7 * 60 + 30));
+        assert_eq!(quiet.to_string(), "22:00-07:30");
+        assert!(QuietHours::parse("22:00").is_none());
+        assert!(QuietHours::parse("25:00-07:00").is_none());
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet = QuietHours::parse("09:00-17:00").unwrap();
+        assert!(!quiet.contains(8 * 60 + 59));
+        assert!(quiet.contains(9 * 60));
+        assert!(quiet.contains(16 * 60 + 59));
+        assert!(!quiet.contains(17 * 60));
+    }
+
+    #[test]
+    fn quiet_hours_wrap_past_midnight() {
+        let quiet = QuietHours::parse("22:00-07:30").unwrap();
+        assert!(quiet.contains(22 * 60));
+        assert!(quiet.contains(23 * 60 + 59));
+        assert!(quiet.contains(0));
+        assert!(quiet.contains(7 * 60 + 29));
+        assert!(!quiet.contains(7 * 60 + 30));
+        assert!(!quiet.contains(12 * 60));
+        assert!(!quiet.contains(21 * 60 + 59));
+    }
+
+    #[test]
+    fn mentions_get_through_mutes_but_not_quiet_hours() {
+        let settings = Settings { muted: vec!["noisy".to_string()], quiet: QuietHours::parse("22:00-07:00"), ..Settings::default() };
+        let noon = 12 * 60;
+        assert!(settings.allows_at("friend", false, noon));
+        assert!(!settings.allows_at("noisy", false, noon));
+        assert!(settings.allows_at("noisy", true, noon));
+        assert!(!settings.allows_at("friend", false, 23 * 60));
+        assert!(!settings.allows_at("noisy", true, 23 * 60));
+        assert!(!settings.allows_at("friend", true, 3 * 60));
+    }
+
+    #[test]
+    fn levels_filter_what_is_left() {
+        let mut settings = Settings { level: Level::Mentions, ..Settings::default() };
+        assert!(!settings.allows_at("friend", false, 0));
+        assert!(settings.allows_at("friend", true, 0));
+        settings.level = Level::Off;
+        assert!(!settings.allows_at("friend", true, 0));
+    }
+}
@@ -0,0 +1,32 @@
+// src/picker.rs
+
+/// One line of a picker, pointing at an entry in one of the buffers, or just at a conversation
+/// for what is only in the local history
+pub struct Item {
+    pub buffer: String,
+    pub entry: Option<usize>,
+    pub text: String,
+}
+
+/// A list shown in place of the history pane, moved through with the arrow keys
+pub struct Picker {
+    pub title: String,
+    pub items: Vec<Item>,
+    pub selected: usize,
+}
+
+impl Picker {
+    pub fn new(title: String, items: Vec<Item>) -> Picker {
+        Picker { title, items, selected: 0 }
+    }
+
+    /// Moves the selection by `step` items, stopping at either end
+    pub fn step(&mut self, step: isize) {
+        let last = self.items.len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(step).min(last);
+    }
+
+    pub fn current(&self) -> Option<&Item> {
+        self.items.get(self.selected)
+    }
+}
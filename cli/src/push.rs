@@ -0,0 +1,59 @@
+// src/push.rs
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    sync::mpsc::Sender,
+    thread,
+};
+use crate::request::{ApiError, Chat, Client};
+use crate::sync::SyncCommand;
+
+/// Follows the server's event stream for one user's chat log, or with `me` the direct messages
+/// between `me` and that user, starting after the first `after` chats, and hands each new chat
+/// to the sync task. Tells it when the stream ends, so it can poll for anything missed and open
+/// a new one from there.
+pub fn spawn(client: Client, id: String, me: Option<String>, after: usize, sync: Sender<SyncCommand>) {
+    thread::spawn(move || {
+        let stream = match &me {
+            Some(me) => client.subscribe_thread(me, &id, after),
+            None => client.subscribe(&id, after),
+        };
+        let unsupported = match stream {
+            Ok(stream) => {
+                follow(stream, &id, &sync);
+                false
+            }
+            // The Go server and the daemon's socket have no event streams
+            Err(ApiError::NoRoute) => true,
+            Err(_) => false,
+        };
+        let _ = sync.send(SyncCommand::Dropped { id, unsupported });
+    });
+}
+
+// Reads server-sent events until the stream ends or breaks. Each carries one chat, with its
+// position in the log as the event id.
+fn follow(stream: Box<dyn Read + Send>, id: &str, sync: &Sender<SyncCommand>) {
+    let mut position = None;
+    let mut data = String::new();
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        if line.is_empty() {
+            // A blank line ends the event
+            if let (Some(position), Ok(chat)) = (position.take(), serde_json::from_str::<Chat>(&data)) {
+                if sync.send(SyncCommand::Pushed { id: id.to_string(), position, chat }).is_err() {
+                    return; // The sync task has gone away
+                }
+            }
+            data.clear();
+        } else if let Some(value) = line.strip_prefix("id:") {
+            position = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+        // Lines starting with a colon are comments that only keep the connection alive
+    }
+}
@@ -1,40 +1,470 @@
 // src/requests.rs
 
-use serde::{Deserialize, Serialize};
-use crate::generate_id; // Import the generateID function from main
+use std::{fmt, io::Read, sync::{Arc, Mutex}};
+use serde::{de::DeserializeOwned, Serialize};
+pub use crate::api::{Chat, Room, Thread, ThreadInfo, User};
+use crate::auth::{Auth, Credentials, Grant, Session};
+use crate::config::Profile;
+use crate::api::{Request, Response};
+use crate::transport::{self, Transport};
+use crate::generate_id;
 
-#[derive(Clone)]
-pub struct Chat {
-    pub chat: String,
-    pub user_id: String,
-    pub user_name: String,
-}
-
-#[derive(Clone)]
-pub struct User {
-    pub name: String,
-    pub id: String,
-    pub chat_log: Vec<Chat>,
-}
-
-pub static mut USERS: Vec<User> = Vec::new();
+pub static USERS: Mutex<Vec<User>> = Mutex::new(Vec::new());
 
-pub fn add_user(name: String) {
+pub fn add_user(name: String) -> User {
     let new_user = User {
         name,
         id: generate_id(), // Use the existing generateID function
         chat_log: Vec::new(),
+        keys: None,
     };
 
-    unsafe {
-        USERS.push(new_user);
-    }
+    USERS.lock().unwrap().push(new_user.clone());
+    new_user
 }
 
 pub fn get_user_by_id(user_id: &str) -> Option<User> {
-    unsafe {
-        USERS.iter().cloned().find(|user| user.id == user_id)
+    USERS.lock().unwrap().iter().find(|user| user.id == user_id).cloned()
+}
+
+// Replaces the known users with the server's list, keeping ourselves in it
+pub fn set_users(me: &User, mut users: Vec<User>) {
+    if !users.iter().any(|user| user.id == me.id) {
+        users.push(me.clone());
+    }
+    *USERS.lock().unwrap() = users;
+}
+
+// Adds a user to the directory, or refreshes its name, until the next full directory fetch
+pub fn remember_user(user: &User) {
+    let mut users = USERS.lock().unwrap();
+    match users.iter_mut().find(|known| known.id == user.id) {
+        Some(known) => known.name = user.name.clone(),
+        None => users.push(User { chat_log: Vec::new(), ..user.clone() }),
+    }
+}
+
+// Looks a user up in the directory by id, or by name ignoring case
+pub fn find_user(name_or_id: &str) -> Option<User> {
+    let users = USERS.lock().unwrap();
+    users
+        .iter()
+        .find(|user| user.id == name_or_id)
+        .or_else(|| users.iter().find(|user| user.name.eq_ignore_ascii_case(name_or_id)))
+        .cloned()
+}
+
+// Names of every known user, for @name completion
+pub fn user_names() -> Vec<String> {
+    USERS.lock().unwrap().iter().map(|user| user.name.clone()).collect()
+}
+
+/// Everything that can go wrong talking to the chat API
+#[derive(Clone, Debug)]
+pub enum ApiError {
+    NotFound,
+    NoRoute, // The server has no such route at all, like the Go server for rooms, threads and events
+    Unauthorized, // The server wants a login, or no longer takes our token
+    Status(u16, String),
+    Tls(String), // The connection was refused over certificates, or our TLS setup is broken
+    Proxy(String), // The proxy could not be reached, turned us away or is set up wrong
+    Transport(String),
+    Decode(String),
+}
+
+impl ApiError {
+    /// The error as it reads for something done to a room
+    pub fn about_room(&self) -> String {
+        match self {
+            ApiError::NotFound => "there is no such room".to_string(),
+            ApiError::NoRoute => "the server has no rooms".to_string(),
+            error => error.to_string(),
+        }
     }
 }
 
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::NoRoute => write!(f, "not something the server does"),
+            ApiError::Unauthorized => write!(f, "not logged in"),
+            ApiError::Status(code, text) => write!(f, "server said {} {}", code, text.trim()),
+            ApiError::Tls(error) | ApiError::Transport(error) => write!(f, "{}", error),
+            ApiError::Proxy(error) => write!(f, "proxy {}", error),
+            ApiError::Decode(error) => write!(f, "bad response: {}", error),
+        }
+    }
+}
+
+/// Whether an answer is the server not knowing the route at all, which is what Go's router sends,
+/// rather than a route not finding what it was asked for
+pub fn no_route(response: &Response) -> bool {
+    response.status == 404 && response.body.trim_ascii() == b"404 page not found"
+}
+
+/// Client for the routes served by api/main.go, over whatever transport the profile's server
+/// calls for. Clones share one login.
+#[derive(Clone)]
+pub struct Client {
+    transport: Arc<dyn Transport>,
+    auth: Arc<Mutex<Auth>>,
+}
+
+impl Client {
+    pub fn new(profile: &Profile) -> Client {
+        Client::with_transport(profile, transport::open(profile))
+    }
 
+    /// A client that sends its requests through `transport`
+    pub fn with_transport(profile: &Profile, transport: Arc<dyn Transport>) -> Client {
+        Client { transport, auth: Arc::new(Mutex::new(Auth::load(profile))) }
+    }
+
+    // Sends one request as it is and sorts the answer into success or ApiError
+    fn send(&self, method: &str, path: &str, token: Option<&str>, body: Option<&serde_json::Value>) -> Result<Response, ApiError> {
+        let request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            token: token.map(str::to_string),
+            body: body.map(|body| serde_json::to_vec(body).unwrap_or_default()),
+        };
+        let response = self.transport.send(&request)?;
+        match response.status {
+            200..=299 => Ok(response),
+            401 => Err(ApiError::Unauthorized),
+            404 if no_route(&response) => Err(ApiError::NoRoute),
+            404 => Err(ApiError::NotFound),
+            code => Err(ApiError::Status(code, String::from_utf8_lossy(&response.body).into_owned())),
+        }
+    }
+
+    /// POST /auth/login - trades a name and secret for a bearer token, which every later call carries
+    pub fn login(&self, name: &str, secret: &str) -> Result<(), ApiError> {
+        let session = self.grant("/auth/login", None, &serde_json::json!({ "name": name, "secret": secret })).map_err(|error| match error {
+            ApiError::Unauthorized => ApiError::Status(401, "wrong name or secret".to_string()),
+            error => error,
+        })?;
+        let mut auth = self.auth.lock().unwrap();
+        auth.credentials = Some(Credentials { name: name.to_string(), secret: secret.to_string() });
+        auth.keep(session).map_err(|error| ApiError::Transport(format!("could not save the session: {}", error)))
+    }
+
+    // Asks the server for a token, with the old one when refreshing
+    fn grant(&self, path: &str, token: Option<&str>, body: &serde_json::Value) -> Result<Session, ApiError> {
+        let response = self.send("POST", path, token, Some(body))?;
+        let grant: Grant = decode(&response)?;
+        Ok(Session::from_grant(grant))
+    }
+
+    // A token good for the next call: the current one, refreshed when it is about to expire, or a
+    // fresh login when there is none and we have credentials. None when the server never asked for one.
+    fn token(&self) -> Result<Option<String>, ApiError> {
+        let mut auth = self.auth.lock().unwrap();
+        let session = match &auth.session {
+            Some(session) if !session.expiring() => return Ok(Some(session.token.clone())),
+            Some(session) => self.grant("/auth/refresh", Some(&session.token), &serde_json::json!({})).ok(),
+            None => None,
+        };
+        let session = match (session, auth.credentials.clone()) {
+            (Some(session), _) => session,
+            (None, Some(credentials)) => self.grant("/auth/login", None, &serde_json::json!({ "name": credentials.name, "secret": credentials.secret }))?,
+            (None, None) => {
+                auth.forget();
+                return Ok(None);
+            }
+        };
+        let token = session.token.clone();
+        let _ = auth.keep(session); // The token still works for this run when it can not be saved
+        Ok(Some(token))
+    }
+
+    // Sends one request with our token. On a 401 the token is dropped and, when we can log in
+    // again on our own, the request is tried once more with a new one.
+    fn call(&self, method: &str, path: &str, body: Option<&serde_json::Value>) -> Result<Response, ApiError> {
+        match self.send(method, path, self.token()?.as_deref(), body) {
+            Err(ApiError::Unauthorized) => {
+                let retry = {
+                    let mut auth = self.auth.lock().unwrap();
+                    auth.forget();
+                    auth.credentials.is_some()
+                };
+                if !retry {
+                    return Err(ApiError::Unauthorized);
+                }
+                self.send(method, path, self.token()?.as_deref(), body)
+            }
+            result => result,
+        }
+    }
+
+    /// Passes on a request from elsewhere, with our token when it brings none of its own. Error
+    /// statuses come back as answers, only failing to reach the server is an error.
+    pub fn forward(&self, request: &Request) -> Result<Response, ApiError> {
+        if request.token.is_some() {
+            return self.transport.send(request);
+        }
+        self.transport.send(&Request { token: self.token()?, ..request.clone() })
+    }
+
+    fn call_json<T: DeserializeOwned>(&self, method: &str, path: &str, body: Option<&serde_json::Value>) -> Result<T, ApiError> {
+        decode(&self.call(method, path, body)?)
+    }
+
+    /// The name we last logged in with, if any
+    pub fn login_name(&self) -> Option<String> {
+        self.auth.lock().unwrap().credentials.as_ref().map(|credentials| credentials.name.clone())
+    }
+
+    /// POST /user/adduser/ - creates the user, or renames it if the id is already known
+    pub fn register(&self, user: &User) -> Result<(), ApiError> {
+        self.call("POST", "/user/adduser/", Some(&to_json(user)?))?;
+        Ok(())
+    }
+
+    /// GET /user/ - every user the server knows about
+    pub fn get_users(&self) -> Result<Vec<User>, ApiError> {
+        // An empty user list comes back as null
+        let users: Option<Vec<User>> = self.call_json("GET", "/user/", None)?;
+        Ok(users.unwrap_or_default())
+    }
+
+    /// GET /user/{id} - the user together with its chat log
+    pub fn get_user(&self, id: &str) -> Result<User, ApiError> {
+        self.call_json("GET", &format!("/user/{}", id), None)
+    }
+
+    /// GET /user/{id}/chats - just the chat log of that user
+    pub fn get_chats(&self, id: &str) -> Result<Vec<Chat>, ApiError> {
+        let chats: Option<Vec<Chat>> = self.call_json("GET", &format!("/user/{}/chats", id), None)?;
+        Ok(chats.unwrap_or_default())
+    }
+
+    /// GET /user/{id}/events?after={n} - a stream of the chats in that user's log past the first
+    /// `after`, then each new one as it is posted. NoRoute when the server has no such route.
+    pub fn subscribe(&self, id: &str, after: usize) -> Result<Box<dyn Read + Send>, ApiError> {
+        self.stream(&format!("/user/{}/events?after={}", id, after))
+    }
+
+    /// GET /dm/{id}/{peer}/events?after={n} - the same for the direct messages between two users
+    pub fn subscribe_thread(&self, id: &str, peer: &str, after: usize) -> Result<Box<dyn Read + Send>, ApiError> {
+        self.stream(&format!("/dm/{}/{}/events?after={}", id, peer, after))
+    }
+
+    fn stream(&self, path: &str) -> Result<Box<dyn Read + Send>, ApiError> {
+        let request = Request { method: "GET".to_string(), path: path.to_string(), token: self.token()?, body: None };
+        self.transport.subscribe(&request)
+    }
+
+    /// POST /user/{id}/chat - appends a chat to that user's chat log
+    pub fn send_chat(&self, id: &str, chat: &Chat) -> Result<Chat, ApiError> {
+        self.call_json("POST", &format!("/user/{}/chat", id), Some(&to_json(chat)?))
+    }
+
+    /// GET /dm/{id}/ - the direct message threads that user is in, without their chats.
+    /// NoRoute when the server has no threads, like the Go server.
+    pub fn get_threads(&self, id: &str) -> Result<Vec<ThreadInfo>, ApiError> {
+        let threads: Option<Vec<ThreadInfo>> = self.call_json("GET", &format!("/dm/{}/", id), None)?;
+        Ok(threads.unwrap_or_default())
+    }
+
+    /// GET /dm/{id}/{peer} - the direct messages between two users, in the order they were sent
+    pub fn get_thread(&self, id: &str, peer: &str) -> Result<Thread, ApiError> {
+        self.call_json("GET", &format!("/dm/{}/{}", id, peer), None)
+    }
+
+    /// POST /dm/{id}/{peer} - sends a direct message from `id` to `peer`, it shows up for both
+    pub fn send_direct(&self, id: &str, peer: &str, chat: &Chat) -> Result<Chat, ApiError> {
+        self.call_json("POST", &format!("/dm/{}/{}", id, peer), Some(&to_json(chat)?))
+    }
+
+    /// The conversation between `id` and `peer`: their thread, or on a server without threads
+    /// the chat log of `peer`, which is where everything sent to them ends up there.
+    /// Returns whether it is a thread.
+    pub fn get_conversation(&self, id: &str, peer: &str) -> Result<(Vec<Chat>, bool), ApiError> {
+        match self.get_thread(id, peer) {
+            Ok(thread) => Ok((thread.chat_log, true)),
+            Err(ApiError::NoRoute) => Ok((self.get_chats(peer)?, false)),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Sends `chat` from `id` to `peer` in their thread, or on a server without threads to the chat log of `peer`
+    pub fn send_to(&self, id: &str, peer: &str, chat: &Chat) -> Result<Chat, ApiError> {
+        match self.send_direct(id, peer, chat) {
+            // Only a server without threads, an unknown sender or peer must not end up in a user log
+            Err(ApiError::NoRoute) => self.send_chat(peer, chat),
+            result => result,
+        }
+    }
+
+    /// GET /room/ - every room and who is in it, without their chats. NoRoute when the
+    /// server has no rooms, like the Go server.
+    pub fn get_rooms(&self) -> Result<Vec<Room>, ApiError> {
+        let rooms: Option<Vec<Room>> = self.call_json("GET", "/room/", None)?;
+        Ok(rooms.unwrap_or_default())
+    }
+
+    /// GET /room/{id} - the room with its members and chat log, by id or by name
+    pub fn get_room(&self, id: &str) -> Result<Room, ApiError> {
+        self.call_json("GET", &format!("/room/{}", id), None)
+    }
+
+    /// POST /room/ - makes a room named `name`, with `user` as its first member
+    pub fn create_room(&self, name: &str, user: &str) -> Result<Room, ApiError> {
+        self.call_json("POST", "/room/", Some(&serde_json::json!({ "name": name, "user": user })))
+    }
+
+    /// POST /room/{id}/join - adds `user` to the room, by id or by name
+    pub fn join_room(&self, id: &str, user: &str) -> Result<Room, ApiError> {
+        self.call_json("POST", &format!("/room/{}/join", id), Some(&serde_json::json!({ "user": user })))
+    }
+
+    /// POST /room/{id}/leave - takes `user` out of the room, by id or by name
+    pub fn leave_room(&self, id: &str, user: &str) -> Result<Room, ApiError> {
+        self.call_json("POST", &format!("/room/{}/leave", id), Some(&serde_json::json!({ "user": user })))
+    }
+
+    /// POST /room/{id}/chat - posts a chat to everyone in the room, as the chat's own user
+    pub fn send_room_chat(&self, id: &str, chat: &Chat) -> Result<Chat, ApiError> {
+        self.call_json("POST", &format!("/room/{}/chat", id), Some(&to_json(chat)?))
+    }
+}
+
+fn decode<T: DeserializeOwned>(response: &Response) -> Result<T, ApiError> {
+    serde_json::from_slice(&response.body).map_err(|error| ApiError::Decode(error.to_string()))
+}
+
+fn to_json(value: &impl Serialize) -> Result<serde_json::Value, ApiError> {
+    serde_json::to_value(value).map_err(|error| ApiError::Decode(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, sync::Once, time::Duration};
+    use chrono::Utc;
+    use meow_common::memory::{Logins, Memory};
+    use super::*;
+    use crate::auth::Session;
+    use crate::transport::InProcess;
+
+    // Every call that reached the server: its path and the token it carried
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    // Sessions are kept under MEOW_HOME, which should not be the real one
+    fn away_from_home() {
+        static HOME: Once = Once::new();
+        HOME.call_once(|| env::set_var("MEOW_HOME", env::temp_dir().join(format!("meow-auth-test-{}", std::process::id()))));
+    }
+
+    // A client for an in-process server that wants logins, and what the server saw
+    fn start(lifetime: Duration) -> (Client, Seen) {
+        away_from_home();
+
+        let secrets = HashMap::from([("alice".to_string(), "s3cret".to_string())]);
+        let memory = Memory::seeded().with_logins(Logins { secrets, lifetime });
+        let seen = Seen::default();
+        let log = seen.clone();
+        let transport = InProcess(Box::new(move |request| {
+            log.lock().unwrap().push((request.path.clone(), request.token.clone()));
+            memory.handle(request)
+        }));
+        let mut profile = Profile::from_env();
+        profile.name = format!("auth-test-{}", random_suffix());
+        (Client::with_transport(&profile, Arc::new(transport)), seen)
+    }
+
+    // A client for an in-process server without logins, and with or without DM threads like the Go server
+    fn open(threads: bool) -> Client {
+        away_from_home();
+        let memory = Memory::seeded();
+        let transport = InProcess(Box::new(move |request| match request.path.starts_with("/dm/") && !threads {
+            true => Response { status: 404, body: b"404 page not found\n".to_vec() },
+            false => memory.handle(request),
+        }));
+        let mut profile = Profile::from_env();
+        profile.name = format!("dm-test-{}", random_suffix());
+        Client::with_transport(&profile, Arc::new(transport))
+    }
+
+    fn random_suffix() -> String {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).unwrap();
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn token(client: &Client) -> String {
+        client.auth.lock().unwrap().session.as_ref().unwrap().token.clone()
+    }
+
+    #[test]
+    fn login_refresh_and_login_again() {
+        let (client, seen) = start(Duration::from_secs(600));
+
+        // Without a login the server turns us away, and there is nothing to log in with
+        assert!(matches!(client.get_users(), Err(ApiError::Unauthorized)));
+        assert!(matches!(client.login("alice", "wrong"), Err(ApiError::Status(401, _))));
+
+        client.login("alice", "s3cret").unwrap();
+        let first = token(&client);
+        client.get_users().unwrap();
+        assert_eq!(seen.lock().unwrap().last().unwrap(), &("/user/".to_string(), Some(first.clone())));
+
+        // A token about to run out is traded for a new one before the call
+        client.auth.lock().unwrap().session.as_mut().unwrap().expires = Some(Utc::now());
+        seen.lock().unwrap().clear();
+        client.get_users().unwrap();
+        let second = token(&client);
+        assert_ne!(first, second);
+        assert_eq!(*seen.lock().unwrap(), [("/auth/refresh".to_string(), Some(first.clone())), ("/user/".to_string(), Some(second.clone()))]);
+
+        // The old token was replaced, so a server that no longer takes ours gets a fresh login
+        client.auth.lock().unwrap().session = Some(Session { token: first.clone(), expires: None });
+        seen.lock().unwrap().clear();
+        client.get_users().unwrap();
+        let third = token(&client);
+        assert_ne!(third, second);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [("/user/".to_string(), Some(first)), ("/auth/login".to_string(), None), ("/user/".to_string(), Some(third))]
+        );
+    }
+
+    #[test]
+    fn stale_tokens_are_turned_down() {
+        // Every token has run out by the time it is used
+        let (client, seen) = start(Duration::ZERO);
+        client.login("alice", "s3cret").unwrap();
+        assert!(matches!(client.get_users(), Err(ApiError::Unauthorized)));
+        let paths: Vec<String> = seen.lock().unwrap().iter().map(|(path, _)| path.clone()).collect();
+        // Refreshing a stale token fails, so the client logs in again, and gives up after one retry
+        assert_eq!(paths, ["/auth/login", "/auth/refresh", "/auth/login", "/user/", "/auth/login", "/user/"]);
+    }
+
+    #[test]
+    fn direct_messages_only_fall_back_without_threads() {
+        let user = |name: &str, id: &str| User { name: name.to_string(), id: id.to_string(), chat_log: Vec::new(), keys: None };
+        let chat = Chat { chat: "hi bob".to_string(), user_id: "a1".to_string(), user_name: "alice".to_string() };
+
+        // A sender the server does not know is an error, not a chat filed in the peer's log
+        let client = open(true);
+        client.register(&user("bob", "b2")).unwrap();
+        assert!(matches!(client.send_to("a1", "b2", &chat), Err(ApiError::NotFound)));
+        assert!(matches!(client.get_conversation("a1", "b2"), Err(ApiError::NotFound)));
+        assert!(client.get_chats("b2").unwrap().is_empty());
+
+        client.register(&user("alice", "a1")).unwrap();
+        client.send_to("a1", "b2", &chat).unwrap();
+        let (chats, thread) = client.get_conversation("a1", "b2").unwrap();
+        assert!(thread && chats.len() == 1);
+        assert!(client.get_chats("b2").unwrap().is_empty());
+
+        // Without threads everything sent to bob ends up in his log
+        let client = open(false);
+        client.register(&user("bob", "b2")).unwrap();
+        client.send_to("a1", "b2", &chat).unwrap();
+        let (chats, thread) = client.get_conversation("a1", "b2").unwrap();
+        assert!(!thread && chats.len() == 1);
+        assert!(matches!(client.get_threads("a1"), Err(ApiError::NoRoute)));
+    }
+}
@@ -0,0 +1,128 @@
+// src/search.rs
+
+use regex::{Regex, RegexBuilder};
+use crate::request::Chat;
+
+// Characters of the message kept in front of a match when it is shown in the results
+const LEAD: usize = 20;
+
+pub const USAGE: &str = "Usage: /search [-r] [from:name] [in:name] words";
+
+/// A parsed /search query: what to look for and where
+pub struct Query {
+    pub text: String,
+    pattern: Regex,
+    pub sender: Option<String>,
+    pub conversation: Option<String>,
+}
+
+impl Query {
+    /// Parses `[-r] [from:name] [in:name] words`. Words are matched as written, ignoring case;
+    /// with -r they are a regular expression instead, still ignoring case unless it says (?-i).
+    pub fn parse(arguments: &str) -> Result<Query, String> {
+        let mut regex = false;
+        let mut sender = None;
+        let mut conversation = None;
+        let mut words = Vec::new();
+
+        for word in arguments.split_whitespace() {
+            if word == "-r" && words.is_empty() {
+                regex = true;
+            } else if let Some(name) = word.strip_prefix("from:").filter(|name| !name.is_empty()) {
+                sender = Some(name.to_string());
+            } else if let Some(name) = word.strip_prefix("in:").filter(|name| !name.is_empty()) {
+                conversation = Some(name.to_string());
+            } else {
+                words.push(word);
+            }
+        }
+
+        let text = words.join(" ");
+        if text.is_empty() && sender.is_none() {
+            return Err(USAGE.to_string());
+        }
+
+        let source = if regex { text.clone() } else { regex::escape(&text) };
+        let pattern = RegexBuilder::new(&source)
+            .case_insensitive(true)
+            .build()
+            .map_err(|error| format!("Bad pattern: {}", error))?;
+
+        Ok(Query { text, pattern, sender, conversation })
+    }
+
+    /// Where the query matches the chat, as a byte range of its text
+    pub fn find(&self, chat: &Chat) -> Option<(usize, usize)> {
+        if let Some(sender) = &self.sender {
+            let sender = sender.trim_start_matches('@');
+            if !chat.user_name.eq_ignore_ascii_case(sender) && !chat.user_id.starts_with(sender) {
+                return None;
+            }
+        }
+        self.pattern.find(&chat.chat).map(|found| (found.start(), found.end()))
+    }
+}
+
+/// The part of a message around a match, on one line, starting a little before the match
+pub fn snippet(text: &str, (start, _): (usize, usize)) -> String {
+    let lead: Vec<(usize, char)> = text[..start].char_indices().collect();
+    let from = lead.len().saturating_sub(LEAD);
+    let cut = lead.get(from).map_or(start, |(index, _)| *index);
+
+    let shown = text[cut..].replace('\n', " ");
+    if cut > 0 { format!("\u{2026}{}", shown) } else { shown }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str, id: &str, name: &str) -> Chat {
+        Chat { chat: text.to_string(), user_id: id.to_string(), user_name: name.to_string() }
+    }
+
+    #[test]
+    fn queries_take_filters_anywhere() {
+        let query = Query::parse("from:bob lunch in:general today").unwrap();
+        assert_eq!(query.text, "lunch today");
+        assert_eq!(query.sender.as_deref(), Some("bob"));
+        assert_eq!(query.conversation.as_deref(), Some("general"));
+
+        // A sender alone is enough, but nothing at all is not
+        assert!(Query::parse("from:bob").is_ok());
+        assert_eq!(Query::parse("in:general").err().unwrap(), USAGE);
+        assert_eq!(Query::parse("  ").err().unwrap(), USAGE);
+        // An empty filter is just a word
+        assert_eq!(Query::parse("from:").unwrap().text, "from:");
+    }
+
+    #[test]
+    fn words_match_as_written_and_patterns_as_regexes() {
+        let message = chat("Meet at 1.30? OK", "b2", "bob");
+        assert_eq!(Query::parse("1.30?").unwrap().find(&message), Some((8, 13)));
+        assert_eq!(Query::parse("ok").unwrap().find(&message), Some((14, 16)));
+        assert!(Query::parse("1x30").unwrap().find(&message).is_none());
+
+        assert_eq!(Query::parse("-r 1.30").unwrap().find(&message), Some((8, 12)));
+        assert!(Query::parse("-r (?-i)ok").unwrap().find(&message).is_none());
+        assert!(Query::parse("-r (").err().unwrap().starts_with("Bad pattern"));
+        // -r only counts in front of the words
+        assert_eq!(Query::parse("meet -r").unwrap().text, "meet -r");
+    }
+
+    #[test]
+    fn senders_match_by_name_or_id() {
+        let message = chat("hello", "b2c3", "Bob");
+        assert!(Query::parse("from:bob hello").unwrap().find(&message).is_some());
+        assert!(Query::parse("from:@BOB hello").unwrap().find(&message).is_some());
+        assert!(Query::parse("from:b2 hello").unwrap().find(&message).is_some());
+        assert!(Query::parse("from:alice hello").unwrap().find(&message).is_none());
+    }
+
+    #[test]
+    fn snippets_start_a_little_before_the_match() {
+        assert_eq!(snippet("short\nline", (0, 5)), "short line");
+        let text = format!("{}match", "x".repeat(30));
+        assert_eq!(snippet(&text, (30, 35)), format!("\u{2026}{}match", "x".repeat(LEAD)));
+    }
+}
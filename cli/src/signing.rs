@@ -0,0 +1,156 @@
+// src/signing.rs
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+use crate::e2e::{announced_keys, Identity, Published};
+use crate::request::Chat;
+
+// Signatures go on a line of their own at the end of the `chat` field, so clients that do not
+// know about them still show the message
+const SIGNATURE_TAG: &str = "\nmeow-sig:1:";
+
+/// Whether a chat provably comes from who it says
+#[derive(Clone, Copy, PartialEq)]
+pub enum Signature {
+    Verified,
+    Unverified, // Not signed, or signed by someone whose key we do not have
+    Forged,
+}
+
+/// Who a signed chat says wrote it, with the signature that should prove it
+pub struct Claim {
+    pub from: String,
+    signature: String,
+}
+
+// What gets signed: the author, the conversation it was posted to and the payload, so a signed
+// chat can neither be passed off as someone else's nor moved to another conversation
+fn message(from: &str, to: &str, body: &str) -> Vec<u8> {
+    [b"meow-sig-v1", from.as_bytes(), to.as_bytes(), body.as_bytes()].join(&0u8)
+}
+
+/// Appends our signature to a payload we post to the conversation `to`
+pub fn sign(identity: &Identity, from: &str, to: &str, body: &str) -> String {
+    format!("{}{}{}:{}", body, SIGNATURE_TAG, identity.sign(&message(from, to, body)), from)
+}
+
+/// Splits a chat into its payload and the signature at its end, if it has one
+pub fn split(text: &str) -> (&str, Option<Claim>) {
+    let Some((body, trailer)) = text.rsplit_once(SIGNATURE_TAG) else { return (text, None) };
+    match trailer.split_once(':') {
+        Some((signature, from)) if !from.is_empty() => (body, Some(Claim { from: from.to_string(), signature: signature.to_string() })),
+        _ => (text, None),
+    }
+}
+
+/// The keys announced for `owner` in `text`, if it is an announcement signed with the signing key it
+/// carries. That only proves whoever posted it holds those keys, not that they are `owner`: anybody
+/// can sign their own keys in someone else's name. Version 1 announcements carry no signing key and
+/// are never taken.
+pub fn announced_by(owner: &str, text: &str) -> Option<Published> {
+    let (body, claim) = split(text);
+    let published = announced_keys(body)?;
+    let sign = published.sign.as_ref()?;
+    let claim = claim.filter(|claim| claim.from == owner)?;
+    (verify(sign, &claim, owner, body) == Signature::Verified).then_some(published)
+}
+
+/// The keys announced for `owner`, first seen first. Anybody can post to anybody's log, so when the
+/// server keeps keys in the profile, which it never replaces once set, those are the only ones taken.
+pub fn announcements(owner: &str, profile: Option<&str>, log: &[Chat]) -> Vec<Published> {
+    if let Some(published) = profile.and_then(|keys| announced_by(owner, keys)) {
+        return vec![published];
+    }
+    log.iter().filter_map(|chat| announced_by(owner, &chat.chat)).collect()
+}
+
+/// Checks a claim on a chat posted to `to` against the signing key `key` of its author
+pub fn verify(key: &str, claim: &Claim, to: &str, body: &str) -> Signature {
+    let key = BASE64.decode(key).ok().and_then(|key| <[u8; 32]>::try_from(key).ok()).and_then(|key| VerifyingKey::from_bytes(&key).ok());
+    let signature = BASE64.decode(&claim.signature).ok().and_then(|signature| <[u8; 64]>::try_from(signature).ok());
+    match (key, signature) {
+        (Some(key), Some(signature)) if key.verify(&message(&claim.from, to, body), &Ed25519Signature::from_bytes(&signature)).is_ok() => Signature::Verified,
+        _ => Signature::Forged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::e2e::tests::identity;
+
+    #[test]
+    fn signatures_tie_a_chat_to_its_author_and_conversation() {
+        let (alice, bob) = (identity("a1"), identity("b2"));
+        let key = alice.published().sign.unwrap();
+        let signed = sign(&alice, "a1", "b2", "hello\nthere");
+
+        let (body, claim) = split(&signed);
+        let claim = claim.unwrap();
+        assert_eq!((body, claim.from.as_str()), ("hello\nthere", "a1"));
+        assert!(verify(&key, &claim, "b2", body) == Signature::Verified);
+
+        // A changed text, another conversation or somebody else's key all fail
+        assert!(verify(&key, &claim, "b2", "hello\nthere!") == Signature::Forged);
+        assert!(verify(&key, &claim, "c3", body) == Signature::Forged);
+        assert!(verify(&bob.published().sign.unwrap(), &claim, "b2", body) == Signature::Forged);
+        // So does claiming someone else wrote it
+        let (_, claim) = split(&signed.replacen(":a1", ":b2", 1));
+        assert!(verify(&key, &claim.unwrap(), "b2", body) == Signature::Forged);
+        let (_, claim) = split(&signed.replacen("meow-sig:1:", "meow-sig:1:AA", 1));
+        assert!(verify(&key, &claim.unwrap(), "b2", body) == Signature::Forged);
+        assert!(verify("not a key", &split(&signed).1.unwrap(), "b2", body) == Signature::Forged);
+    }
+
+    #[test]
+    fn chats_without_a_signature_split_as_they_are() {
+        assert!(split("plain text").1.is_none());
+        // A signature line without an author is part of the text
+        let text = "odd\nmeow-sig:1:abc:";
+        let (body, claim) = split(text);
+        assert!(body == text && claim.is_none());
+    }
+
+    #[test]
+    fn announcements_have_to_be_signed_by_their_keys() {
+        let (alice, mallory) = (identity("a1"), identity("m9"));
+        let text = alice.announcement("a1");
+        assert!(announced_by("a1", &text) == Some(alice.published()));
+
+        // Posted without a signature, or signed by someone else, or claimed for another user
+        let (body, _) = split(&text);
+        assert!(announced_by("a1", body).is_none());
+        assert!(announced_by("a1", &sign(&mallory, "a1", "a1", body)).is_none());
+        assert!(announced_by("m9", &text).is_none());
+        // Mallory's seal key next to Alice's signing key, signed by Mallory
+        let forged = format!("meow-key:2:{}:{}", mallory.public_key(), alice.published().sign.unwrap());
+        assert!(announced_by("a1", &sign(&mallory, "a1", "a1", &forged)).is_none());
+    }
+
+    #[test]
+    fn version_1_announcements_are_refused() {
+        // Nothing to check them against, so anybody could post one for anybody
+        let mallory = identity("m9");
+        let old = format!("meow-key:1:{}", mallory.public_key());
+        assert!(announced_by("a1", &old).is_none());
+        assert!(announced_by("a1", &sign(&mallory, "a1", "a1", &old)).is_none());
+    }
+
+    #[test]
+    fn the_profile_wins_over_the_log() {
+        let (alice, mallory) = (identity("a1"), identity("m9"));
+        let posted = |text: String| Chat { chat: text, user_id: "a1".to_string(), user_name: "alice".to_string() };
+        // Mallory's own keys, signed by her in Alice's name, pass the signature check: it only proves
+        // she holds them. Posted to the log first, they would be the first seen.
+        let log = [posted(mallory.announcement("a1")), posted(alice.announcement("a1"))];
+        assert!(announced_by("a1", &log[0].chat) == Some(mallory.published()));
+        assert!(announcements("a1", None, &log) == [mallory.published(), alice.published()]);
+
+        // The keys in the profile are the only ones taken when there are any
+        let profile = alice.announcement("a1");
+        assert!(announcements("a1", Some(&profile), &log) == [alice.published()]);
+        // A profile that does not check out counts as none
+        assert!(announcements("a1", Some("meow-key:2:junk"), &log).len() == 2);
+        assert!(announcements("a1", None, &[]).is_empty());
+    }
+}
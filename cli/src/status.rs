@@ -0,0 +1,86 @@
+// src/status.rs
+
+use std::io::{self, Stdout};
+use crossterm::{cursor, execute, style::{ContentStyle, PrintStyledContent}};
+use crate::layout::{display_width, truncate};
+use crate::theme::Theme;
+
+/// How the sync task is getting on with the server
+#[derive(Clone, PartialEq)]
+pub enum Connection {
+    Connecting,
+    Connected,
+    Reconnecting { attempt: u32, error: String },
+    Offline { error: String },
+}
+
+/// Everything shown on the status line at the top of the screen
+pub struct Status {
+    pub username: String,
+    pub short_id: String,
+    pub profile: String,
+    pub connection: Connection,
+    pub peer: Option<String>,
+    pub unread: usize,
+    pub sealed: bool, // Whether messages to the peer are end-to-end encrypted
+    pub busy: usize,  // Calls to the server still on their way
+    pub frame: usize, // Turns the spinner shown while busy
+}
+
+// What the busy spinner cycles through
+const SPINNER: &[char] = &['\u{280b}', '\u{2819}', '\u{2839}', '\u{2838}', '\u{283c}', '\u{2834}', '\u{2826}', '\u{2827}', '\u{2807}', '\u{280f}'];
+
+// Keep the color of `style` but put it on the status line background
+fn on_bar(style: ContentStyle, bar: ContentStyle) -> ContentStyle {
+    ContentStyle {
+        foreground_color: style.foreground_color.or(bar.foreground_color),
+        attributes: bar.attributes | style.attributes,
+        ..bar
+    }
+}
+
+impl Status {
+    fn segments(&self, theme: &Theme) -> Vec<(String, ContentStyle)> {
+        let health = match &self.connection {
+            Connection::Connecting => ("\u{25cb} connecting".to_string(), theme.warn),
+            Connection::Connected => ("\u{25cf} connected".to_string(), theme.ok),
+            Connection::Reconnecting { attempt, error } => (format!("\u{25d0} reconnecting #{}: {}", attempt, error), theme.warn),
+            Connection::Offline { error } => (format!("\u{25cb} offline: {}", error), theme.error),
+        };
+
+        let mut segments = vec![
+            (format!(" {} #{} ", self.username, self.short_id), theme.status),
+            (format!("| {} ", self.profile), theme.status),
+            ("| ".to_string(), theme.status),
+            (health.0 + " ", on_bar(health.1, theme.status)),
+        ];
+        if self.busy > 0 {
+            segments.push((format!("| {} busy ", SPINNER[self.frame % SPINNER.len()]), theme.status));
+        }
+        if let Some(peer) = &self.peer {
+            segments.push((format!("| \u{2192} {} ", peer), theme.status));
+        }
+        if self.sealed {
+            segments.push(("| e2e ".to_string(), on_bar(theme.ok, theme.status)));
+        }
+        if self.unread > 0 {
+            segments.push((format!("| {} unread ", self.unread), on_bar(theme.warn, theme.status)));
+        }
+        segments
+    }
+
+    /// Draws the status line across row 0, cutting it short on narrow terminals
+    pub fn draw(&self, stdout: &mut Stdout, cols: u16, theme: &Theme) -> io::Result<()> {
+        let mut room = cols as usize;
+        execute!(stdout, cursor::MoveTo(0, 0))?;
+
+        for (text, style) in self.segments(theme) {
+            let text = truncate(&text, room);
+            room -= display_width(&text);
+            execute!(stdout, PrintStyledContent(style.apply(text)))?;
+        }
+
+        // Fill the rest of the row so the bar spans the whole width
+        execute!(stdout, PrintStyledContent(theme.status.apply(" ".repeat(room))))
+    }
+}
@@ -0,0 +1,70 @@
+// src/store.rs
+
+use std::{collections::HashMap, env, fs, io, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::config::Profile;
+use crate::notify;
+
+/// Local state kept between runs, one JSON file per server profile
+#[derive(Default, Serialize, Deserialize)]
+pub struct Store {
+    #[serde(skip)]
+    path: PathBuf,
+    /// How many chats of each conversation's server log have been read
+    #[serde(default)]
+    pub read: HashMap<String, usize>,
+    /// Conversations that were open, reopened on the next run
+    #[serde(default)]
+    pub open: Vec<String>,
+    /// Mutes, quiet hours and mention-only mode
+    #[serde(default)]
+    pub notify: notify::Settings,
+    /// Conversations whose messages are sealed end to end
+    #[serde(default)]
+    pub encrypted: Vec<String>,
+    /// Public keys pinned per user id, the first key seen for someone is trusted from then on
+    #[serde(default)]
+    pub pins: HashMap<String, String>,
+    /// Signing keys pinned per user id, the same way
+    #[serde(default)]
+    pub signers: HashMap<String, String>,
+}
+
+/// Where meow keeps its files: MEOW_HOME, else the XDG data directory
+pub fn data_dir() -> PathBuf {
+    if let Some(home) = env::var_os("MEOW_HOME") {
+        return PathBuf::from(home);
+    }
+    match env::var_os("XDG_DATA_HOME") {
+        Some(data) => PathBuf::from(data).join("meow"),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local/share/meow"),
+    }
+}
+
+/// Writes `value` as JSON, going through a temporary file so a crash never leaves half a file
+pub fn save_json(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&temporary, path)
+}
+
+impl Store {
+    /// Loads the store for a profile, starting empty when there is none yet or it can not be read
+    pub fn open(profile: &Profile) -> Store {
+        let path = data_dir().join(format!("{}.json", profile.name));
+        let mut store: Store = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        store.path = path;
+        store
+    }
+
+    /// Writes the store back to disk
+    pub fn save(&self) -> io::Result<()> {
+        save_json(&self.path, self)
+    }
+}
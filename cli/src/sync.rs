@@ -0,0 +1,228 @@
+// src/sync.rs
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+use crate::push;
+use crate::request::{ApiError, Chat, Client, Room, ThreadInfo, User};
+use crate::status::Connection;
+
+// How often watched chat logs are fetched while the server is reachable, for those the server does not push
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Failed attempts in a row before the connection is reported as offline
+const MAX_RETRIES: u32 = 5;
+// Longest wait between retries once the server stops answering
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// The user directory changes rarely, so it is only fetched every this many rounds
+const DIRECTORY_ROUNDS: u32 = 15;
+
+/// What the sync task reports back to the TUI
+pub enum SyncEvent {
+    Connection(Connection),
+    Users(Vec<User>),
+    User(User),
+    Room(Room),
+    Thread { peer: String, chat_log: Vec<Chat> }, // The direct messages between us and `peer`
+    Missing(String), // A user or room the server does not have
+    Reregistered,
+    LoginNeeded, // The server turned our token down and we have nothing to log in with
+}
+
+/// What the TUI asks of the sync task, and what the push streams tell it
+pub enum SyncCommand {
+    Watch(String),
+    WatchRoom(String),
+    UnwatchRoom(String),
+    Pushed { id: String, position: usize, chat: Chat }, // A new chat and its position in the log, counting from 1
+    Dropped { id: String, unsupported: bool },          // A push stream ended, `unsupported` when the server has none
+}
+
+// What the sync task knows about the users and rooms it follows
+struct Followed {
+    logs: HashMap<String, User>, // The last copy of each, which pushed chats are added to
+    live: HashSet<String>,       // Those with a push stream open, which need no polling
+    push: bool,                  // Cleared once the server turns out to have no push streams
+    pushes: Sender<SyncCommand>, // Where the push streams send what they hear
+    rooms: Vec<String>,          // Rooms we are in, polled every round
+    threads: Option<HashMap<String, Vec<Chat>>>, // The direct messages with each peer, None once the server turns out to have none
+}
+
+/// Starts the background sync task. It registers `me` with the server, then keeps
+/// fetching every watched user (starting with `me`) and reports what it finds.
+/// Where the server pushes new chats it follows those streams instead, and only
+/// polls every so often to check nothing was missed.
+/// The client is shared with the TUI, so a login there is picked up here.
+pub fn spawn(client: Client, me: User) -> (Sender<SyncCommand>, Receiver<SyncEvent>) {
+    let (command_tx, command_rx) = channel();
+    let (event_tx, event_rx) = channel();
+
+    let pushes = command_tx.clone();
+    thread::spawn(move || run(client, me, command_rx, pushes, event_tx));
+
+    (command_tx, event_rx)
+}
+
+fn run(client: Client, me: User, commands: Receiver<SyncCommand>, pushes: Sender<SyncCommand>, events: Sender<SyncEvent>) {
+    let mut followed = Followed { logs: HashMap::new(), live: HashSet::new(), push: true, pushes, rooms: Vec::new(), threads: Some(HashMap::new()) };
+    let mut watched = vec![me.id.clone()];
+    let mut registered = false;
+    let mut failures: u32 = 0;
+    let mut connection = Connection::Connecting;
+    let mut round: u32 = 0;
+    let mut asked = false; // Whether the TUI was asked to log in since the last good round
+
+    loop {
+        let directory = round.is_multiple_of(DIRECTORY_ROUNDS);
+        let result = poll_once(&client, &me, &watched, &mut registered, directory, &mut followed, &events);
+
+        // Work out the new connection state and how long to wait before the next round
+        let (next, delay) = match result {
+            Ok(()) => {
+                failures = 0;
+                asked = false;
+                round += 1;
+                (Connection::Connected, POLL_INTERVAL)
+            }
+            Err(error) => {
+                if matches!(error, ApiError::Unauthorized) && !asked {
+                    asked = true;
+                    let _ = events.send(SyncEvent::LoginNeeded);
+                }
+                failures += 1;
+                let backoff = POLL_INTERVAL.saturating_mul(1 << failures.min(4)).min(MAX_BACKOFF);
+                let error = error.to_string();
+                if failures > MAX_RETRIES {
+                    (Connection::Offline { error }, MAX_BACKOFF)
+                } else {
+                    (Connection::Reconnecting { attempt: failures, error }, backoff)
+                }
+            }
+        };
+        if next != connection {
+            connection = next;
+            if events.send(SyncEvent::Connection(connection.clone())).is_err() {
+                return; // The TUI has gone away
+            }
+        }
+
+        // Sleep until the next round, taking pushed chats as they come and waking early when the TUI asks for something
+        let wake = Instant::now() + delay;
+        loop {
+            match commands.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+                Ok(SyncCommand::Watch(id)) => {
+                    if !watched.contains(&id) {
+                        watched.push(id);
+                    }
+                    break;
+                }
+                Ok(SyncCommand::WatchRoom(id)) => {
+                    if !followed.rooms.contains(&id) {
+                        followed.rooms.push(id);
+                    }
+                    break;
+                }
+                Ok(SyncCommand::UnwatchRoom(id)) => followed.rooms.retain(|room| *room != id),
+                Ok(SyncCommand::Pushed { id, position, chat }) => {
+                    // Chats already fetched come again when a stream opens, and are skipped
+                    if let Some(thread) = followed.threads.as_mut().and_then(|threads| threads.get_mut(&id)) {
+                        if position == thread.len() + 1 {
+                            thread.push(chat);
+                            let _ = events.send(SyncEvent::Thread { peer: id, chat_log: thread.clone() });
+                        }
+                        continue;
+                    }
+                    let Some(user) = followed.logs.get_mut(&id) else { continue };
+                    if position == user.chat_log.len() + 1 {
+                        user.chat_log.push(chat);
+                        let _ = events.send(SyncEvent::User(user.clone()));
+                    }
+                }
+                // Polled again next round, which picks up anything missed and opens a new stream from there
+                Ok(SyncCommand::Dropped { id, unsupported }) => {
+                    followed.live.remove(&id);
+                    followed.push &= !unsupported;
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+// One round of syncing, any transport or server error aborts the round. Users with a push stream
+// are left alone except on directory rounds.
+fn poll_once(client: &Client, me: &User, watched: &[String], registered: &mut bool, directory: bool, followed: &mut Followed, events: &Sender<SyncEvent>) -> Result<(), ApiError> {
+    if !*registered {
+        client.register(me)?;
+        *registered = true;
+    }
+
+    if directory {
+        let _ = events.send(SyncEvent::Users(client.get_users()?));
+    }
+
+    // Direct messages are in the threads we are in, which are listed to find new ones. Without
+    // threads the server files them under whoever they were sent to, so their logs are followed instead.
+    if followed.threads.is_some() {
+        match client.get_threads(&me.id) {
+            Ok(listed) => follow_threads(client, me, listed, followed, events)?,
+            Err(ApiError::NoRoute) => followed.threads = None,
+            Err(error) => return Err(error),
+        }
+    }
+
+    for id in watched {
+        if (!directory && followed.live.contains(id)) || (*id != me.id && followed.threads.is_some()) {
+            continue;
+        }
+        let event = match client.get_user(id) {
+            Ok(user) => {
+                if followed.push && followed.live.insert(id.clone()) {
+                    push::spawn(client.clone(), id.clone(), None, user.chat_log.len(), followed.pushes.clone());
+                }
+                followed.logs.insert(id.clone(), user.clone());
+                SyncEvent::User(user)
+            }
+            // We are gone as well, so the server restarted and lost everyone. Sign up again right away.
+            Err(ApiError::NotFound) if *id == me.id => {
+                client.register(me)?;
+                SyncEvent::Reregistered
+            }
+            Err(ApiError::NotFound) => SyncEvent::Missing(id.clone()),
+            Err(error) => return Err(error),
+        };
+        // Nobody is listening any more, the thread ends on the next recv
+        let _ = events.send(event);
+    }
+
+    for id in &followed.rooms {
+        let event = match client.get_room(id) {
+            Ok(room) => SyncEvent::Room(room),
+            Err(ApiError::NotFound) => SyncEvent::Missing(id.clone()),
+            Err(error) => return Err(error),
+        };
+        let _ = events.send(event);
+    }
+    Ok(())
+}
+
+// Fetches the threads that grew since the last round and opens a push stream for each one without
+fn follow_threads(client: &Client, me: &User, listed: Vec<ThreadInfo>, followed: &mut Followed, events: &Sender<SyncEvent>) -> Result<(), ApiError> {
+    let Some(threads) = &mut followed.threads else { return Ok(()) };
+    for info in listed {
+        // Notes to ourselves stay in our own chat log
+        let Some(peer) = info.users.into_iter().find(|user| *user != me.id) else { continue };
+        if threads.get(&peer).map(Vec::len) != Some(info.length) {
+            let chat_log = client.get_thread(&me.id, &peer)?.chat_log;
+            threads.insert(peer.clone(), chat_log.clone());
+            let _ = events.send(SyncEvent::Thread { peer: peer.clone(), chat_log });
+        }
+        if followed.push && followed.live.insert(peer.clone()) {
+            push::spawn(client.clone(), peer.clone(), Some(me.id.clone()), threads[&peer].len(), followed.pushes.clone());
+        }
+    }
+    Ok(())
+}
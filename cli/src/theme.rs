@@ -0,0 +1,172 @@
+// src/theme.rs
+
+use std::env;
+use crossterm::style::{Attribute, Color, ContentStyle};
+
+pub const THEME_NAMES: [&str; 4] = ["dark", "light", "high-contrast", "no-color"];
+
+// Colors handed out to sender names, picked by hashing the user id
+const DARK_PALETTE: [Color; 8] = [
+    Color::Cyan, Color::Green, Color::Yellow, Color::Magenta,
+    Color::Blue, Color::Red, Color::DarkCyan, Color::DarkYellow,
+];
+const LIGHT_PALETTE: [Color; 6] = [
+    Color::DarkBlue, Color::DarkGreen, Color::DarkMagenta,
+    Color::DarkRed, Color::DarkCyan, Color::DarkYellow,
+];
+const HIGH_CONTRAST_PALETTE: [Color; 5] = [
+    Color::White, Color::Yellow, Color::Cyan, Color::Green, Color::Magenta,
+];
+
+/// The styles used to draw the screen
+#[derive(Clone)]
+pub struct Theme {
+    pub name: &'static str,
+    pub border: ContentStyle,
+    pub text: ContentStyle,
+    pub own_name: ContentStyle,
+    pub own_text: ContentStyle,
+    pub system: ContentStyle,
+    pub error: ContentStyle,
+    pub status: ContentStyle,
+    pub ok: ContentStyle,
+    pub warn: ContentStyle,
+    pub mention: ContentStyle,
+    palette: &'static [Color],
+    bold_names: bool,
+}
+
+fn fg(color: Color) -> ContentStyle {
+    ContentStyle { foreground_color: Some(color), ..ContentStyle::new() }
+}
+
+fn bold(mut style: ContentStyle) -> ContentStyle {
+    style.attributes.set(Attribute::Bold);
+    style
+}
+
+// Reverse video marks the status line without using any color
+fn reverse() -> ContentStyle {
+    let mut style = ContentStyle::new();
+    style.attributes.set(Attribute::Reverse);
+    style
+}
+
+fn bar(foreground: Color, background: Color) -> ContentStyle {
+    ContentStyle { foreground_color: Some(foreground), background_color: Some(background), ..ContentStyle::new() }
+}
+
+impl Theme {
+    /// Looks a theme up by name, returns None for unknown names
+    pub fn named(name: &str) -> Option<Theme> {
+        let theme = match name {
+            "dark" => Theme {
+                name: "dark",
+                border: fg(Color::DarkGrey),
+                text: ContentStyle::new(),
+                own_name: bold(fg(Color::White)),
+                own_text: fg(Color::Grey),
+                system: fg(Color::DarkGrey),
+                error: bold(fg(Color::Red)),
+                status: bar(Color::White, Color::DarkGrey),
+                ok: fg(Color::Green),
+                warn: fg(Color::Yellow),
+                mention: bold(fg(Color::Yellow)),
+                palette: &DARK_PALETTE,
+                bold_names: true,
+            },
+            "light" => Theme {
+                name: "light",
+                border: fg(Color::Grey),
+                text: ContentStyle::new(),
+                own_name: bold(fg(Color::Black)),
+                own_text: fg(Color::DarkGrey),
+                system: fg(Color::DarkGrey),
+                error: bold(fg(Color::DarkRed)),
+                status: bar(Color::Black, Color::Grey),
+                ok: fg(Color::DarkGreen),
+                warn: fg(Color::DarkYellow),
+                mention: bold(fg(Color::DarkMagenta)),
+                palette: &LIGHT_PALETTE,
+                bold_names: true,
+            },
+            "high-contrast" => Theme {
+                name: "high-contrast",
+                border: fg(Color::White),
+                text: fg(Color::White),
+                own_name: bold(ContentStyle {
+                    foreground_color: Some(Color::Black),
+                    background_color: Some(Color::White),
+                    ..ContentStyle::new()
+                }),
+                own_text: fg(Color::White),
+                system: bold(fg(Color::Yellow)),
+                error: bold(ContentStyle {
+                    foreground_color: Some(Color::White),
+                    background_color: Some(Color::Red),
+                    ..ContentStyle::new()
+                }),
+                status: bold(bar(Color::Black, Color::White)),
+                ok: bold(fg(Color::Green)),
+                warn: bold(fg(Color::Yellow)),
+                mention: bold(bar(Color::Black, Color::Yellow)),
+                palette: &HIGH_CONTRAST_PALETTE,
+                bold_names: true,
+            },
+            "no-color" => Theme {
+                name: "no-color",
+                border: ContentStyle::new(),
+                text: ContentStyle::new(),
+                own_name: ContentStyle::new(),
+                own_text: ContentStyle::new(),
+                system: ContentStyle::new(),
+                error: ContentStyle::new(),
+                status: reverse(),
+                ok: ContentStyle::new(),
+                warn: ContentStyle::new(),
+                mention: bold(ContentStyle::new()),
+                palette: &[],
+                bold_names: false,
+            },
+            _ => return None,
+        };
+        Some(theme)
+    }
+
+    /// Picks the theme from MEOW_THEME, falling back to dark.
+    /// NO_COLOR, or a terminal without color support, always wins and gives no-color.
+    pub fn from_env() -> (Theme, Option<String>) {
+        if !colors_allowed() {
+            return (Theme::named("no-color").unwrap(), None);
+        }
+
+        match env::var("MEOW_THEME") {
+            Ok(name) => match Theme::named(&name) {
+                Some(theme) => (theme, None),
+                None => (Theme::named("dark").unwrap(), Some(format!("Unknown theme '{}', using dark", name))),
+            },
+            Err(_) => (Theme::named("dark").unwrap(), None),
+        }
+    }
+
+    /// Stable color for a sender name, the same user id always gets the same color
+    pub fn name_style(&self, user_id: &str) -> ContentStyle {
+        if self.palette.is_empty() {
+            return ContentStyle::new();
+        }
+
+        let color = self.palette[(fnv1a(user_id) % self.palette.len() as u64) as usize];
+        if self.bold_names { bold(fg(color)) } else { fg(color) }
+    }
+}
+
+/// False when NO_COLOR is set or stdout can not show colors
+pub fn colors_allowed() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && console::colors_enabled()
+}
+
+// Small stable hash so name colors do not change between runs or builds
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,125 @@
+// src/tls.rs
+
+use std::{error::Error as _, io, sync::Arc};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    AlertDescription, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use crate::config::Profile;
+
+/// The TLS setup for a profile, or None when ureq's defaults will do
+pub fn config(profile: &Profile) -> Result<Option<Arc<ClientConfig>>, String> {
+    if profile.ca_file.is_none() && profile.client_cert.is_none() && !profile.insecure {
+        return Ok(None);
+    }
+
+    // The same provider and versions ureq uses on its own
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])
+        .map_err(|error| error.to_string())?;
+
+    let builder = if profile.insecure {
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+    } else {
+        // A custom CA comes on top of the public ones, so other servers still work
+        let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        if let Some(path) = &profile.ca_file {
+            let certificates = read_certificates(path)?;
+            let (added, _) = roots.add_parsable_certificates(certificates);
+            if added == 0 {
+                return Err(format!("no usable CA certificate in {}", path.display()));
+            }
+        }
+        builder.with_root_certificates(roots)
+    };
+
+    let config = match (&profile.client_cert, &profile.client_key) {
+        (Some(cert), key) => {
+            // The key may sit in the same file as the certificate
+            let key_path = key.as_ref().unwrap_or(cert);
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(|error| format!("no private key in {}: {}", key_path.display(), error))?;
+            builder.with_client_auth_cert(read_certificates(cert)?, key).map_err(|error| format!("bad client certificate: {}", error))?
+        }
+        (None, Some(_)) => return Err("MEOW_CLIENT_KEY needs MEOW_CLIENT_CERT".to_string()),
+        (None, None) => builder.with_no_client_auth(),
+    };
+    Ok(Some(Arc::new(config)))
+}
+
+fn read_certificates(path: &std::path::Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificates in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// Puts a failed TLS handshake into words, None when the error was not about TLS
+pub fn describe(error: &ureq::Transport) -> Option<String> {
+    // ureq wraps the rustls error in an io::Error
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<rustls::Error>() {
+            return Some(explain(error));
+        }
+        source = match error.downcast_ref::<io::Error>() {
+            Some(error) => error.get_ref().map(|inner| inner as _),
+            None => error.source(),
+        };
+    }
+    None
+}
+
+fn explain(error: &rustls::Error) -> String {
+    match error {
+        rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer) => "certificate from an unknown CA, set MEOW_CA".to_string(),
+        rustls::Error::InvalidCertificate(CertificateError::Expired | CertificateError::ExpiredContext { .. }) => "server certificate has expired".to_string(),
+        rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. }) => {
+            "certificate is for another host".to_string()
+        }
+        rustls::Error::InvalidCertificate(CertificateError::Revoked) => "server certificate was revoked".to_string(),
+        rustls::Error::AlertReceived(AlertDescription::CertificateRequired | AlertDescription::HandshakeFailure) => {
+            "server wants a client certificate, set MEOW_CLIENT_CERT".to_string()
+        }
+        rustls::Error::AlertReceived(AlertDescription::BadCertificate | AlertDescription::UnknownCA) => {
+            "server turned our client certificate down".to_string()
+        }
+        rustls::Error::InvalidMessage(_) => "not a TLS server, try http://".to_string(),
+        error => format!("TLS failed: {}", error),
+    }
+}
+
+// For --insecure: any certificate goes, but handshake signatures are still checked so the
+// connection itself works as usual
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,227 @@
+// src/transport.rs
+
+use std::{
+    error::Error as _,
+    io::{Read, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use crate::api::{Request, Response};
+use crate::config::Profile;
+use crate::memory::Memory;
+use crate::request::ApiError;
+use crate::{proxy, tls};
+
+// How long to wait for the server to accept a connection, and then for each read
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// An event stream can be quiet for a while, but the server sends a comment every 15 seconds
+const EVENTS_READ_TIMEOUT: Duration = Duration::from_secs(40);
+
+/// Carries requests to a chat server and brings back its answers
+pub trait Transport: Send + Sync {
+    fn send(&self, request: &Request) -> Result<Response, ApiError>;
+
+    /// Opens a stream of server-sent events, read until either side hangs up. NoRoute when
+    /// the server or the transport has no such thing.
+    fn subscribe(&self, _request: &Request) -> Result<Box<dyn Read + Send>, ApiError> {
+        Err(ApiError::NoRoute)
+    }
+}
+
+/// The transport for a profile's MEOW_SERVER: `unix:<path>` for a local daemon's socket,
+/// `memory:` for a stand-in server inside this process, and http(s):// for everything else
+pub fn open(profile: &Profile) -> Arc<dyn Transport> {
+    let server = profile.base_url.as_str();
+    if let Some(path) = server.strip_prefix("unix:") {
+        return Arc::new(Unix { path: PathBuf::from(path.trim_start_matches("//")) });
+    }
+    if server == "memory:" {
+        let memory = Memory::seeded();
+        return Arc::new(InProcess(Box::new(move |request| memory.handle(request))));
+    }
+    match Http::new(profile) {
+        Ok(http) => Arc::new(http),
+        Err(error) => Arc::new(Broken(error)),
+    }
+}
+
+/// HTTP and HTTPS through ureq, with the profile's TLS and proxy settings
+pub struct Http {
+    base_url: String,
+    agent: ureq::Agent,
+    events: ureq::Agent, // The same, waiting longer for each read
+    proxy: Option<String>, // The proxy in use, as shown in errors
+}
+
+impl Http {
+    pub fn new(profile: &Profile) -> Result<Http, ApiError> {
+        let tls = tls::config(profile).map_err(ApiError::Tls)?;
+        let proxy = proxy::pick(profile);
+        let parsed = match &proxy {
+            Some(url) => Some(ureq::Proxy::new(url).map_err(|_| ApiError::Proxy(format!("{} is not a proxy URL", proxy::label(url))))?),
+            None => None,
+        };
+        let agent = |read_timeout| {
+            let mut agent = ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).timeout_read(read_timeout);
+            if let Some(config) = &tls {
+                agent = agent.tls_config(config.clone());
+            }
+            if let Some(proxy) = &parsed {
+                agent = agent.proxy(proxy.clone());
+            }
+            agent.build()
+        };
+
+        Ok(Http {
+            base_url: profile.base_url.trim_end_matches('/').to_string(),
+            agent: agent(READ_TIMEOUT),
+            events: agent(EVENTS_READ_TIMEOUT),
+            proxy: proxy.as_deref().map(proxy::label),
+        })
+    }
+
+    // Failing to connect is the proxy's doing when there is one
+    fn error(&self, transport: ureq::Transport) -> ApiError {
+        if let Some(problem) = tls::describe(&transport) {
+            return ApiError::Tls(problem);
+        }
+        let Some(proxy) = &self.proxy else { return ApiError::Transport(transport.to_string()) };
+        match transport.kind() {
+            ureq::ErrorKind::ProxyUnauthorized => ApiError::Proxy(format!("{} wants a login, put user:password@ in its URL", proxy)),
+            ureq::ErrorKind::ProxyConnect => ApiError::Proxy(format!("{} could not reach the server", proxy)),
+            ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Dns | ureq::ErrorKind::Io => {
+                ApiError::Proxy(format!("{}: {}", proxy, transport.source().map_or_else(|| transport.to_string(), |source| source.to_string())))
+            }
+            _ => ApiError::Transport(transport.to_string()),
+        }
+    }
+}
+
+impl Transport for Http {
+    fn send(&self, request: &Request) -> Result<Response, ApiError> {
+        let mut call = self.agent.request(&request.method, &format!("{}{}", self.base_url, request.path));
+        if let Some(token) = &request.token {
+            call = call.set("Authorization", &format!("Bearer {}", token));
+        }
+        let result = match &request.body {
+            Some(body) => call.set("Content-Type", "application/json").send_bytes(body),
+            None => call.call(),
+        };
+
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(ureq::Error::Transport(transport)) => return Err(self.error(transport)),
+        };
+        let status = response.status();
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body).map_err(|error| ApiError::Transport(error.to_string()))?;
+        Ok(Response { status, body })
+    }
+
+    fn subscribe(&self, request: &Request) -> Result<Box<dyn Read + Send>, ApiError> {
+        let mut call = self.events.get(&format!("{}{}", self.base_url, request.path)).set("Accept", "text/event-stream");
+        if let Some(token) = &request.token {
+            call = call.set("Authorization", &format!("Bearer {}", token));
+        }
+        match call.call() {
+            Ok(response) if response.content_type() == "text/event-stream" => Ok(response.into_reader()),
+            // Something answered that does not know about events, an old server or a proxy's page
+            Ok(_) | Err(ureq::Error::Status(404 | 405, _)) => Err(ApiError::NoRoute),
+            Err(ureq::Error::Status(401, _)) => Err(ApiError::Unauthorized),
+            Err(ureq::Error::Status(code, response)) => Err(ApiError::Status(code, response.into_string().unwrap_or_default())),
+            Err(ureq::Error::Transport(transport)) => Err(self.error(transport)),
+        }
+    }
+}
+
+/// Plain HTTP/1.1 over a Unix domain socket, for a daemon on the same machine
+pub struct Unix {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Transport for Unix {
+    fn send(&self, request: &Request) -> Result<Response, ApiError> {
+        use std::os::unix::net::UnixStream;
+        let failed = |error: std::io::Error| ApiError::Transport(format!("{}: {}", self.path.display(), error));
+
+        let mut stream = UnixStream::connect(&self.path).map_err(failed)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT)).map_err(failed)?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT)).map_err(failed)?;
+
+        let body = request.body.as_deref().unwrap_or_default();
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n", request.method, request.path, body.len());
+        if request.body.is_some() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        if let Some(token) = &request.token {
+            head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(body)).map_err(failed)?;
+
+        // One request per connection, so the answer ends where the stream does
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).map_err(failed)?;
+        parse_response(&answer).ok_or_else(|| ApiError::Decode("not an HTTP response".to_string()))
+    }
+}
+
+#[cfg(not(unix))]
+impl Transport for Unix {
+    fn send(&self, _request: &Request) -> Result<Response, ApiError> {
+        Err(ApiError::Transport(format!("{}: Unix sockets are not supported here", self.path.display())))
+    }
+}
+
+// Splits an HTTP/1.1 response read to its end into status and body
+fn parse_response(answer: &[u8]) -> Option<Response> {
+    let split = answer.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&answer[..split]).ok()?;
+    let body = &answer[split + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let chunked = lines.any(|line| {
+        line.split_once(':')
+            .is_some_and(|(name, value)| name.eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked"))
+    });
+    let body = if chunked { dechunk(body)? } else { body.to_vec() };
+    Some(Response { status, body })
+}
+
+// Undoes chunked transfer encoding
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Hands requests straight to a handler in this process, no sockets involved
+pub struct InProcess(pub Box<dyn Fn(&Request) -> Response + Send + Sync>);
+
+impl Transport for InProcess {
+    fn send(&self, request: &Request) -> Result<Response, ApiError> {
+        Ok((self.0)(request))
+    }
+}
+
+// Stands in when the profile's TLS or proxy setup can not be used, so every call reports why
+struct Broken(ApiError);
+
+impl Transport for Broken {
+    fn send(&self, _request: &Request) -> Result<Response, ApiError> {
+        Err(self.0.clone())
+    }
+}
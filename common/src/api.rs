@@ -0,0 +1,80 @@
+// src/api.rs
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+// Field names follow the JSON tags in api/main.go
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Chat {
+    pub chat: String,
+    #[serde(rename = "user", default)]
+    pub user_id: String,
+    #[serde(rename = "name", default)]
+    pub user_name: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub id: String,
+    #[serde(rename = "chats", default, deserialize_with = "null_as_empty")]
+    pub chat_log: Vec<Chat>,
+    /// The user's public key announcement. Only our own server keeps it, the Go server drops the field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<String>,
+}
+
+/// A named channel, a chat posted to it is delivered to every member
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Room {
+    pub name: String,
+    pub id: String,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub members: Vec<String>, // User ids, in the order they joined
+    #[serde(rename = "chats", default, deserialize_with = "null_as_empty")]
+    pub chat_log: Vec<Chat>,
+}
+
+/// The direct messages between two users. Both see the same log in the same order, each chat
+/// with who really sent it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Thread {
+    pub users: Vec<String>, // The two user ids, sorted
+    #[serde(rename = "chats", default, deserialize_with = "null_as_empty")]
+    pub chat_log: Vec<Chat>,
+}
+
+impl Thread {
+    /// The `users` of the thread between `a` and `b`, the same whichever way round
+    pub fn between(a: &str, b: &str) -> Vec<String> {
+        let mut users = vec![a.to_string(), b.to_string()];
+        users.sort();
+        users
+    }
+}
+
+/// A thread as listed, without its chats
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThreadInfo {
+    pub users: Vec<String>,
+    pub length: usize,
+}
+
+// The Go server encodes an empty chat log as null
+fn null_as_empty<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    Ok(Option::<Vec<T>>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// One call to the chat API, the same whatever carries it
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub token: Option<String>,
+    pub body: Option<Vec<u8>>, // JSON
+}
+
+/// What came back. Error statuses are answers too, only failing to get one is an error.
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
@@ -0,0 +1,8 @@
+// src/lib.rs
+
+// What meow-cli and meow-server share: the API's JSON types, the Go routes answered from
+// memory, the storage behind them and the bit of HTTP needed to serve them
+pub mod api;
+pub mod memory;
+pub mod serve;
+pub mod storage;
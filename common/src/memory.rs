@@ -0,0 +1,505 @@
+// src/memory.rs
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::api::{Chat, Request, Response, Room, Thread, ThreadInfo, User};
+use crate::serve::Reply;
+use crate::storage::{Storage, Volatile};
+
+// How often a quiet event stream gets a comment, so a client that went away is noticed
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// The routes of api/main.go answered from memory, with the same JSON and the same quirks.
+/// Every change is written through to a storage, which may keep it past a restart.
+pub struct Memory {
+    state: Mutex<State>,
+    posted: Condvar, // Woken whenever a chat is added, for the event streams
+}
+
+struct State {
+    users: Vec<User>,
+    rooms: Vec<Room>,
+    threads: Vec<Thread>,
+    storage: Box<dyn Storage>,
+    logins: Option<Logins>,
+    tokens: HashMap<String, Instant>, // Bearer tokens handed out, with when each stops working
+}
+
+/// Who may log in and how long their tokens last. With these every route but `/auth/login`
+/// wants a bearer token, without them everything is open like the Go server.
+pub struct Logins {
+    pub secrets: HashMap<String, String>, // Secret per user name
+    pub lifetime: Duration,
+}
+
+// A chat log an event stream follows
+enum Log {
+    User(String),
+    Thread(Vec<String>),
+}
+
+impl State {
+    // The chats in a log so far, none when it is not there (yet)
+    fn chats(&self, log: &Log) -> &[Chat] {
+        match log {
+            Log::User(id) => self.users.iter().find(|user| user.id == *id).map_or(&[], |user| &user.chat_log),
+            Log::Thread(users) => self.threads.iter().find(|thread| thread.users == *users).map_or(&[], |thread| &thread.chat_log),
+        }
+    }
+
+    fn known(&self, id: &str) -> bool {
+        self.users.iter().any(|user| user.id == id)
+    }
+
+    // Whether a request may go through: always without logins, else with a token that has not run out
+    fn authorized(&self, request: &Request) -> bool {
+        self.logins.is_none()
+            || request.token.as_ref().and_then(|token| self.tokens.get(token)).is_some_and(|expires| Instant::now() < *expires)
+    }
+
+    // Hands out a new token, answered the way the client's Grant expects
+    fn grant(&mut self) -> Response {
+        let lifetime = self.logins.as_ref().map_or(Duration::ZERO, |logins| logins.lifetime);
+        let token = random_id();
+        self.tokens.insert(token.clone(), Instant::now() + lifetime);
+        json_response(&json!({ "token": token, "expires_in": lifetime.as_secs() }))
+    }
+
+    // POST /auth/login and /auth/refresh, which the Go server does not have
+    fn auth(&mut self, request: &Request, action: &str, body: &Value) -> Response {
+        let Some(logins) = &self.logins else { return error(404, "404 page not found") };
+        if request.method != "POST" {
+            return error(405, "Method Not Allowed");
+        }
+        match action {
+            "login" => {
+                if logins.secrets.get(&field(body, "name")) != Some(&field(body, "secret")) {
+                    return unauthorized();
+                }
+                self.grant()
+            }
+            "refresh" => {
+                // Only a token that still works gets a new one, which replaces it
+                if !self.authorized(request) {
+                    return unauthorized();
+                }
+                if let Some(token) = &request.token {
+                    self.tokens.remove(token);
+                }
+                self.grant()
+            }
+            _ => error(404, "404 page not found"),
+        }
+    }
+}
+
+// What `GET /` lists
+const USAGE: &[&str] = &[
+    "GET /user/ - Get all users",
+    "GET /user/{id} - Get user by ID",
+    "GET /user/name/{name} - Get user by name",
+    "POST /user/adduser - Add a new user",
+    "PUT /user/{id} - Update an existing user",
+    "POST /user/{id}/chat - Add a chat to user",
+    "PUT /user/{id}/chat/{chatIndex} - Update a chat in user's chat log",
+    "GET /user/{id}/chats - Get all chats for a user",
+    "GET /user/{id}/events?after={n} - Stream new chats for a user as server-sent events",
+    "GET /room/ - Get all rooms",
+    "POST /room/ - Create a room",
+    "GET /room/{id} - Get a room by ID or name",
+    "POST /room/{id}/join - Add a user to a room",
+    "POST /room/{id}/leave - Take a user out of a room",
+    "POST /room/{id}/chat - Post a chat to everyone in a room",
+    "GET /room/{id}/chats - Get all chats in a room",
+    "GET /dm/{id}/ - List the direct message threads a user is in",
+    "GET /dm/{id}/{peer} - Get the direct messages between two users",
+    "POST /dm/{id}/{peer} - Send a direct message from one user to another",
+    "GET /dm/{id}/{peer}/events?after={n} - Stream new direct messages between two users as server-sent events",
+    "POST /auth/login - Trade a name and secret for a bearer token, on servers that want one",
+    "POST /auth/refresh - Trade a bearer token that still works for a new one",
+];
+
+// A random id like the Go server's generateID
+fn random_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("the operating system has no random numbers");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Go encodes a nil slice as null and ends every JSON answer with a line break
+fn json_response(value: &impl Serialize) -> Response {
+    let mut body = serde_json::to_vec(value).unwrap_or_default();
+    body.push(b'\n');
+    Response { status: 200, body }
+}
+
+fn user_json(user: &User) -> Value {
+    let chats = if user.chat_log.is_empty() { Value::Null } else { json!(user.chat_log) };
+    let mut json = json!({ "name": user.name, "id": user.id, "chats": chats });
+    if let Some(keys) = &user.keys {
+        json["keys"] = json!(keys);
+    }
+    json
+}
+
+fn chats_json(chats: &[Chat]) -> Value {
+    if chats.is_empty() { Value::Null } else { json!(chats) }
+}
+
+// What http.Error sends
+fn error(status: u16, text: &str) -> Response {
+    Response { status, body: format!("{}\n", text).into_bytes() }
+}
+
+// Nothing was changed when the storage turned a change down
+fn not_saved(problem: &str) -> Response {
+    error(500, &format!("Could not save: {}", problem))
+}
+
+// Missing or mistyped fields are left empty rather than failing, like Go's decoder for strings
+fn field(body: &Value, name: &str) -> String {
+    body.get(name).and_then(Value::as_str).unwrap_or_default().to_string()
+}
+
+// Rooms go in URLs by name as well as by id, so their names are single words
+fn room_name(body: &Value) -> Option<String> {
+    let name = field(body, "name");
+    let name = name.trim().trim_start_matches('#');
+    (!name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == '/')).then(|| name.to_string())
+}
+
+// Room routes take the room's id or its name
+fn find_room<'a>(rooms: &'a mut [Room], key: &str) -> Option<&'a mut Room> {
+    rooms.iter_mut().find(|room| room.id == key || room.name == key)
+}
+
+fn unauthorized() -> Response {
+    error(401, "Unauthorized")
+}
+
+// Rooms only take users the server knows, and chats only from their members
+fn unknown_user() -> Response {
+    error(403, "Unknown user, sign up first")
+}
+
+fn chat_from(body: &Value) -> Chat {
+    Chat { chat: field(body, "chat"), user_id: field(body, "user"), user_name: field(body, "name") }
+}
+
+impl Memory {
+    /// Forgets everything when it goes away, like the Go server
+    pub fn seeded() -> Memory {
+        Memory::open(Box::new(Volatile)).expect("keeping nothing can not fail")
+    }
+
+    /// Picks up whatever the storage kept. A storage with nothing in it starts out with the
+    /// two users the Go server makes on startup.
+    pub fn open(mut storage: Box<dyn Storage>) -> Result<Memory, String> {
+        let mut users = storage.load()?;
+        let rooms = storage.load_rooms()?;
+        let threads = storage.load_threads()?;
+        if users.is_empty() {
+            for name in ["awa03", "TestUser3"] {
+                let user = User { name: name.to_string(), id: random_id(), chat_log: Vec::new(), keys: None };
+                storage.add_user(&user)?;
+                users.push(user);
+            }
+        }
+        let state = State { users, rooms, threads, storage, logins: None, tokens: HashMap::new() };
+        Ok(Memory { state: Mutex::new(state), posted: Condvar::new() })
+    }
+
+    /// Wants a bearer token on every route from now on, handed out to the users in `logins`
+    pub fn with_logins(self, logins: Logins) -> Memory {
+        self.state.lock().unwrap().logins = Some(logins);
+        self
+    }
+
+    /// Answers like `handle`, and with an event stream for `GET /user/{id}/events` and
+    /// `GET /dm/{id}/{peer}/events`
+    pub fn reply(&self, request: &Request) -> Reply<'_> {
+        let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let (log, ids) = match segments.as_slice() {
+            ["user", id, "events"] => (Log::User(id.to_string()), vec![*id]),
+            ["dm", id, peer, "events"] => (Log::Thread(Thread::between(id, peer)), vec![*id, *peer]),
+            _ => return Reply::Whole(self.handle(request)),
+        };
+        if request.method != "GET" {
+            return Reply::Whole(error(405, "Method Not Allowed"));
+        }
+        let state = self.state.lock().unwrap();
+        if !state.authorized(request) {
+            return Reply::Whole(unauthorized());
+        }
+        if !ids.iter().all(|id| state.known(id)) {
+            return Reply::Whole(error(404, "User not found"));
+        }
+        drop(state);
+        let after = query.split('&').find_map(|pair| pair.strip_prefix("after=")).and_then(|after| after.parse().ok()).unwrap_or(0);
+        Reply::Events(Box::new(move |out| self.events(&log, after, out)))
+    }
+
+    // Sends every chat in the log past the first `sent`, then each new one as it is posted.
+    // An event's id is its chat's position in the log counting from 1, so a client that
+    // reconnects with the last id it saw as `after` picks up where it left off.
+    fn events(&self, log: &Log, mut sent: usize, out: &mut dyn Write) -> io::Result<()> {
+        let unsent = |state: &State, sent: usize| -> Vec<Chat> { state.chats(log).get(sent..).unwrap_or_default().to_vec() };
+        loop {
+            // The lock is let go before writing, so a slow client holds up no one else
+            let (fresh, quiet) = {
+                let state = self.state.lock().unwrap();
+                let fresh = unsent(&state, sent);
+                if fresh.is_empty() {
+                    let (state, waited) = self.posted.wait_timeout(state, KEEPALIVE).unwrap();
+                    (unsent(&state, sent), waited.timed_out())
+                } else {
+                    (fresh, false)
+                }
+            };
+            if fresh.is_empty() && quiet {
+                out.write_all(b": still here\n\n")?;
+            }
+            for chat in fresh {
+                sent += 1;
+                write!(out, "id: {}\ndata: {}\n\n", sent, serde_json::to_string(&chat).unwrap_or_default())?;
+            }
+            out.flush()?;
+        }
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let body: Option<Value> = request.body.as_deref().map(serde_json::from_slice).transpose().unwrap_or(None);
+        let path = request.path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut state = self.state.lock().unwrap();
+
+        // Every POST and PUT route decodes a body first and turns away anything that is not JSON
+        if matches!(request.method.as_str(), "POST" | "PUT") && body.is_none() {
+            return error(400, "Invalid input");
+        }
+        let body = body.unwrap_or(Value::Null);
+
+        if let ["auth", action] = segments.as_slice() {
+            return state.auth(request, action, &body);
+        }
+        if !state.authorized(request) {
+            return unauthorized();
+        }
+        let State { users, rooms, threads, storage, .. } = &mut *state;
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", [""]) => json_response(&json!({
+                "commands": USAGE,
+                "examples": [
+                    r#"curl -X POST -d '{"name": "NewUser"}' http://localhost:4343/user/adduser"#,
+                    r#"curl -X PUT -d '{"name": "UpdatedUser"}' http://localhost:4343/user/{id}"#,
+                    r#"curl -X POST -d '{"chat": "Hello!"}' http://localhost:4343/user/{id}/chat"#,
+                    r#"curl -X POST -d '{"name": "general", "user": "{id}"}' http://localhost:4343/room/"#,
+                ],
+            })),
+            ("GET", ["user", ""]) => {
+                let all: Vec<Value> = users.iter().map(user_json).collect();
+                json_response(&if all.is_empty() { Value::Null } else { Value::Array(all) })
+            }
+            ("POST", ["user", "adduser", ""]) => {
+                let (name, id) = (field(&body, "name"), field(&body, "id"));
+                let keys = body.get("keys").and_then(Value::as_str).map(str::to_string);
+                if let Some(user) = users.iter_mut().find(|user| user.id == id) {
+                    if let Err(problem) = storage.rename(&id, &name) {
+                        return not_saved(&problem);
+                    }
+                    user.name = name;
+                    // The first keys published stay, whoever sends others later
+                    if let (None, Some(keys)) = (&user.keys, keys) {
+                        if let Err(problem) = storage.set_keys(&id, &keys) {
+                            return not_saved(&problem);
+                        }
+                        user.keys = Some(keys);
+                    }
+                    return Response { status: 200, body: Vec::new() };
+                }
+                if id.is_empty() {
+                    return error(400, "ID is required");
+                }
+                let chat_log = body.get("chats").and_then(Value::as_array).map_or_else(Vec::new, |chats| chats.iter().map(chat_from).collect());
+                let user = User { name, id, chat_log, keys };
+                if let Err(problem) = storage.add_user(&user) {
+                    return not_saved(&problem);
+                }
+                let answer = json_response(&user_json(&user));
+                users.push(user);
+                answer
+            }
+            ("GET", ["user", "name", name]) => match users.iter().find(|user| user.name == *name) {
+                Some(user) => json_response(&user_json(user)),
+                None => error(404, "User not found"),
+            },
+            ("GET", ["user", "check", name]) => {
+                let status = if users.iter().any(|user| user.name == *name) { 200 } else { 404 };
+                Response { status, body: Vec::new() }
+            }
+            ("POST", ["user", "name", name, "chat"]) => {
+                match users.iter_mut().find(|user| user.name == *name || user.id == *name) {
+                    Some(user) => post_chat(storage.as_mut(), &self.posted, user, &body),
+                    None => error(404, "User not found"),
+                }
+            }
+            ("GET", ["user", id]) => match users.iter().find(|user| user.id == *id) {
+                Some(user) => json_response(&user_json(user)),
+                None => error(404, "User not found"),
+            },
+            ("PUT", ["user", id]) => match users.iter_mut().find(|user| user.id == *id) {
+                Some(user) => {
+                    let name = field(&body, "name");
+                    if let Err(problem) = storage.rename(id, &name) {
+                        return not_saved(&problem);
+                    }
+                    user.name = name;
+                    json_response(&user_json(user))
+                }
+                None => error(404, "User not found"),
+            },
+            ("POST", ["user", id, "chat"]) => match users.iter_mut().find(|user| user.id == *id) {
+                Some(user) => post_chat(storage.as_mut(), &self.posted, user, &body),
+                None => error(404, "User not found"),
+            },
+            ("PUT", ["user", id, "chat", index]) => {
+                let Some(user) = users.iter_mut().find(|user| user.id == *id) else { return error(404, "User not found") };
+                let Some(index) = index.parse::<usize>().ok().filter(|index| *index < user.chat_log.len()) else {
+                    return error(404, "Chat not found");
+                };
+                let chat = chat_from(&body);
+                if let Err(problem) = storage.set_chat(id, index, &chat) {
+                    return not_saved(&problem);
+                }
+                user.chat_log[index] = chat;
+                json_response(&user.chat_log[index])
+            }
+            ("GET", ["user", id, "chats"]) => match users.iter().find(|user| user.id == *id) {
+                Some(user) => json_response(&chats_json(&user.chat_log)),
+                None => error(404, "User not found"),
+            },
+            ("GET", ["room", ""]) => {
+                // Just who is where, the chats come with each room on its own
+                let all: Vec<Value> = rooms.iter().map(|room| json!({ "name": room.name, "id": room.id, "members": room.members })).collect();
+                json_response(&all)
+            }
+            ("POST", ["room", ""]) => {
+                let Some(name) = room_name(&body) else { return error(400, "A room name is one word, without slashes") };
+                let user = field(&body, "user");
+                if !users.iter().any(|known| known.id == user) {
+                    return unknown_user();
+                }
+                if rooms.iter().any(|room| room.name == name) {
+                    return error(409, "Room already exists");
+                }
+                let room = Room { name, id: random_id(), members: vec![user], chat_log: Vec::new() };
+                if let Err(problem) = storage.add_room(&room) {
+                    return not_saved(&problem);
+                }
+                let answer = json_response(&room);
+                rooms.push(room);
+                answer
+            }
+            ("GET", ["room", key]) => match find_room(rooms, key) {
+                Some(room) => json_response(room),
+                None => error(404, "Room not found"),
+            },
+            ("POST", ["room", key, change @ ("join" | "leave")]) => {
+                let Some(room) = find_room(rooms, key) else { return error(404, "Room not found") };
+                let user = field(&body, "user");
+                if !users.iter().any(|known| known.id == user) {
+                    return unknown_user();
+                }
+                // Joining twice or leaving a room we are not in changes nothing
+                let mut members = room.members.clone();
+                members.retain(|member| *member != user);
+                if *change == "join" {
+                    members.push(user);
+                }
+                if members != room.members {
+                    if let Err(problem) = storage.set_members(&room.id, &members) {
+                        return not_saved(&problem);
+                    }
+                    room.members = members;
+                }
+                json_response(room)
+            }
+            ("POST", ["room", key, "chat"]) => {
+                let Some(room) = find_room(rooms, key) else { return error(404, "Room not found") };
+                let Some(sender) = users.iter().find(|user| user.id == field(&body, "user")) else { return unknown_user() };
+                if !room.members.contains(&sender.id) {
+                    return error(403, "Not a member of this room");
+                }
+                // Unlike a user's chat log, a room keeps who sent each chat, with the name the server knows them by
+                let chat = Chat { chat: field(&body, "chat"), user_id: sender.id.clone(), user_name: sender.name.clone() };
+                if let Err(problem) = storage.add_room_chat(&room.id, &chat) {
+                    return not_saved(&problem);
+                }
+                room.chat_log.push(chat.clone());
+                self.posted.notify_all();
+                json_response(&chat)
+            }
+            ("GET", ["room", key, "chats"]) => match find_room(rooms, key) {
+                Some(room) => json_response(&room.chat_log),
+                None => error(404, "Room not found"),
+            },
+            ("GET", ["dm", id, ""]) => {
+                // Someone nobody wrote to yet, or the server does not know, is in no threads
+                let listed: Vec<ThreadInfo> = threads
+                    .iter()
+                    .filter(|thread| thread.users.iter().any(|user| user == id))
+                    .map(|thread| ThreadInfo { users: thread.users.clone(), length: thread.chat_log.len() })
+                    .collect();
+                json_response(&listed)
+            }
+            ("GET", ["dm", id, peer]) => {
+                if !users.iter().any(|user| user.id == *id) || !users.iter().any(|user| user.id == *peer) {
+                    return error(404, "User not found");
+                }
+                let between = Thread::between(id, peer);
+                match threads.iter().find(|thread| thread.users == between) {
+                    Some(thread) => json_response(thread),
+                    None => json_response(&Thread { users: between, chat_log: Vec::new() }),
+                }
+            }
+            ("POST", ["dm", id, peer]) => {
+                let Some(sender) = users.iter().find(|user| user.id == *id) else { return error(404, "User not found") };
+                if !users.iter().any(|user| user.id == *peer) {
+                    return error(404, "User not found");
+                }
+                // The chat is from whoever the path says, under the name the server knows them by
+                let chat = Chat { chat: field(&body, "chat"), user_id: sender.id.clone(), user_name: sender.name.clone() };
+                let between = Thread::between(id, peer);
+                if let Err(problem) = storage.add_direct(&between, &chat) {
+                    return not_saved(&problem);
+                }
+                match threads.iter_mut().find(|thread| thread.users == between) {
+                    Some(thread) => thread.chat_log.push(chat.clone()),
+                    None => threads.push(Thread { users: between, chat_log: vec![chat.clone()] }),
+                }
+                self.posted.notify_all();
+                json_response(&chat)
+            }
+            _ => error(404, "404 page not found"),
+        }
+    }
+}
+
+// The chat is filed under the user it was posted to, whoever sent it
+fn post_chat(storage: &mut dyn Storage, posted: &Condvar, user: &mut User, body: &Value) -> Response {
+    let chat = Chat { chat: field(body, "chat"), user_id: user.id.clone(), user_name: user.name.clone() };
+    if let Err(problem) = storage.add_chat(&user.id, &chat) {
+        return not_saved(&problem);
+    }
+    user.chat_log.push(chat.clone());
+    posted.notify_all();
+    json_response(&chat)
+}
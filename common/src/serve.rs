@@ -0,0 +1,102 @@
+// src/serve.rs
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use crate::api::{Request, Response};
+
+// Requests bigger than this are turned away rather than read into memory
+const MAX_BODY: usize = 1 << 20;
+
+/// Writes server-sent events to the connection for as long as the client stays
+pub type Events<'a> = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + 'a>;
+
+/// How a request is answered: all at once, or with an event stream
+pub enum Reply<'a> {
+    Whole(Response),
+    Events(Events<'a>),
+}
+
+/// Answers one HTTP/1.1 request on `stream` with `handler`, then closes it
+pub fn connection<S: Read + Write>(stream: S, handler: &dyn Fn(&Request) -> Response) -> io::Result<()> {
+    streaming(stream, &|request| Reply::Whole(handler(request)))
+}
+
+/// Like `connection`, for handlers that may answer with an event stream
+pub fn streaming<'a, S: Read + Write>(stream: S, handler: &dyn Fn(&Request) -> Reply<'a>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let reply = match read_request(&mut reader)? {
+        Some(request) => handler(&request),
+        None => Reply::Whole(Response { status: 400, body: b"Bad request\n".to_vec() }),
+    };
+    let stream = reader.get_mut();
+    match reply {
+        Reply::Whole(response) => write_response(stream, &response),
+        Reply::Events(events) => {
+            // No length, the stream ends when one side hangs up
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+            stream.flush()?;
+            events(stream)
+        }
+    }
+}
+
+// Reads the request line, the headers we care about and the body. None when it is not HTTP.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else { return Ok(None) };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut length = 0;
+    let mut token = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else { continue };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().unwrap_or(0);
+        } else if name.eq_ignore_ascii_case("authorization") {
+            token = value.strip_prefix("Bearer ").map(str::to_string);
+        }
+    }
+    if length > MAX_BODY {
+        return Ok(None);
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let body = (!body.is_empty()).then_some(body);
+    Ok(Some(Request { method, path, token, body }))
+}
+
+fn write_response(stream: &mut impl Write, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        _ => "",
+    };
+    // Answers that are not JSON are http.Error's plain text, the same as the Go server
+    let kind = if response.status == 200 { "application/json" } else { "text/plain; charset=utf-8" };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        kind,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
@@ -0,0 +1,81 @@
+// src/storage.rs
+
+use crate::api::{Chat, Room, Thread, User};
+
+/// Where a server keeps its users and their chats. The routes hold a copy of everything and
+/// write each change through to here before answering, so a failed write changes nothing.
+pub trait Storage: Send {
+    /// Everything kept so far, users in the order they were added
+    fn load(&mut self) -> Result<Vec<User>, String>;
+    fn add_user(&mut self, user: &User) -> Result<(), String>;
+    fn rename(&mut self, id: &str, name: &str) -> Result<(), String>;
+    /// Records the key announcement a user published
+    fn set_keys(&mut self, id: &str, keys: &str) -> Result<(), String>;
+    /// Appends to the end of a user's chat log
+    fn add_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String>;
+    fn set_chat(&mut self, id: &str, index: usize, chat: &Chat) -> Result<(), String>;
+    /// Every room kept so far, in the order they were made
+    fn load_rooms(&mut self) -> Result<Vec<Room>, String>;
+    fn add_room(&mut self, room: &Room) -> Result<(), String>;
+    /// Replaces who is in a room
+    fn set_members(&mut self, id: &str, members: &[String]) -> Result<(), String>;
+    /// Appends to the end of a room's chat log
+    fn add_room_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String>;
+    /// Every DM thread kept so far
+    fn load_threads(&mut self) -> Result<Vec<Thread>, String>;
+    /// Appends to the end of the thread between `users`, starting it when there is none yet
+    fn add_direct(&mut self, users: &[String], chat: &Chat) -> Result<(), String>;
+}
+
+/// Keeps nothing beyond the routes' own copy, so like the Go server everything is gone with the process
+pub struct Volatile;
+
+impl Storage for Volatile {
+    fn load(&mut self) -> Result<Vec<User>, String> {
+        Ok(Vec::new())
+    }
+
+    fn add_user(&mut self, _user: &User) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn rename(&mut self, _id: &str, _name: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn set_keys(&mut self, _id: &str, _keys: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn add_chat(&mut self, _id: &str, _chat: &Chat) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn set_chat(&mut self, _id: &str, _index: usize, _chat: &Chat) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load_rooms(&mut self) -> Result<Vec<Room>, String> {
+        Ok(Vec::new())
+    }
+
+    fn add_room(&mut self, _room: &Room) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn set_members(&mut self, _id: &str, _members: &[String]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn add_room_chat(&mut self, _id: &str, _chat: &Chat) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn load_threads(&mut self) -> Result<Vec<Thread>, String> {
+        Ok(Vec::new())
+    }
+
+    fn add_direct(&mut self, _users: &[String], _chat: &Chat) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+// src/contract.rs
+
+// What every storage has to do, checked the same way for each backend
+
+use meow_common::api::{Chat, Room, Thread, User};
+use meow_common::storage::Storage;
+
+fn chat(text: &str, from: &str) -> Chat {
+    Chat { chat: text.to_string(), user_id: from.to_string(), user_name: format!("{}'s name", from) }
+}
+
+fn user(name: &str, id: &str, chat_log: Vec<Chat>) -> User {
+    User { name: name.to_string(), id: id.to_string(), chat_log, keys: None }
+}
+
+// Who said what in a chat log, in order
+fn said(chats: &[Chat]) -> Vec<(&str, &str, &str)> {
+    chats.iter().map(|chat| (chat.user_id.as_str(), chat.user_name.as_str(), chat.chat.as_str())).collect()
+}
+
+/// Writes through every method of `storage`, which starts out empty, then checks that what
+/// `reopen` gives, a new storage on the same place, loads it all back in the same order
+pub fn round_trip(mut storage: Box<dyn Storage>, reopen: impl FnOnce() -> Box<dyn Storage>) {
+    assert!(storage.load().unwrap().is_empty());
+    assert!(storage.load_rooms().unwrap().is_empty());
+    assert!(storage.load_threads().unwrap().is_empty());
+
+    storage.add_user(&user("alice", "a1", vec![chat("imported", "a1")])).unwrap();
+    storage.add_user(&user("bob", "b2", Vec::new())).unwrap();
+    storage.add_user(&user("carol", "c3", Vec::new())).unwrap();
+    storage.rename("b2", "robert").unwrap();
+    storage.add_user(&User { keys: Some("meow-key:2:dave".to_string()), ..user("dave", "d4", Vec::new()) }).unwrap();
+    storage.set_keys("b2", "meow-key:2:bob").unwrap();
+    storage.add_chat("b2", &chat("hello", "b2")).unwrap();
+    storage.add_chat("b2", &chat("typo", "b2")).unwrap();
+    storage.add_chat("a1", &chat("later", "a1")).unwrap();
+    storage.set_chat("b2", 1, &chat("fixed", "b2")).unwrap();
+
+    let room = |name: &str, id: &str, members: &[&str]| Room {
+        name: name.to_string(),
+        id: id.to_string(),
+        members: members.iter().map(|member| member.to_string()).collect(),
+        chat_log: Vec::new(),
+    };
+    storage.add_room(&room("general", "r1", &["a1"])).unwrap();
+    storage.add_room(&room("random", "r2", &["c3"])).unwrap();
+    storage.set_members("r1", &["a1".to_string(), "b2".to_string()]).unwrap();
+    storage.set_members("r2", &[]).unwrap();
+    storage.add_room_chat("r1", &chat("hi all", "b2")).unwrap();
+    storage.add_room_chat("r1", &chat("hi bob", "a1")).unwrap();
+
+    storage.add_direct(&Thread::between("b2", "a1"), &chat("psst", "b2")).unwrap();
+    storage.add_direct(&Thread::between("c3", "a1"), &chat("other", "c3")).unwrap();
+    storage.add_direct(&Thread::between("a1", "b2"), &chat("back", "a1")).unwrap();
+
+    // Changing what is not there is an error rather than nothing at all
+    assert!(storage.rename("nobody", "ghost").is_err());
+    assert!(storage.set_keys("nobody", "meow-key:2:ghost").is_err());
+    assert!(storage.set_chat("b2", 5, &chat("nowhere", "b2")).is_err());
+    assert!(storage.set_chat("nobody", 0, &chat("nowhere", "b2")).is_err());
+    assert!(storage.add_chat("nobody", &chat("lost", "a1")).is_err());
+    assert!(storage.set_members("no-room", &["a1".to_string()]).is_err());
+    assert!(storage.set_members("no-room", &[]).is_err());
+    assert!(storage.add_room_chat("no-room", &chat("lost", "a1")).is_err());
+    drop(storage);
+
+    let mut storage = reopen();
+    let users = storage.load().unwrap();
+    let names: Vec<(&str, &str)> = users.iter().map(|user| (user.id.as_str(), user.name.as_str())).collect();
+    assert_eq!(names, [("a1", "alice"), ("b2", "robert"), ("c3", "carol"), ("d4", "dave")]);
+    let keys: Vec<Option<&str>> = users.iter().map(|user| user.keys.as_deref()).collect();
+    assert_eq!(keys, [None, Some("meow-key:2:bob"), None, Some("meow-key:2:dave")]);
+    assert_eq!(said(&users[0].chat_log), [("a1", "a1's name", "imported"), ("a1", "a1's name", "later")]);
+    assert_eq!(said(&users[1].chat_log), [("b2", "b2's name", "hello"), ("b2", "b2's name", "fixed")]);
+    assert!(users[2].chat_log.is_empty());
+
+    let rooms = storage.load_rooms().unwrap();
+    let names: Vec<(&str, &str)> = rooms.iter().map(|room| (room.id.as_str(), room.name.as_str())).collect();
+    assert_eq!(names, [("r1", "general"), ("r2", "random")]);
+    assert_eq!(rooms[0].members, ["a1", "b2"]);
+    assert!(rooms[1].members.is_empty());
+    assert_eq!(said(&rooms[0].chat_log), [("b2", "b2's name", "hi all"), ("a1", "a1's name", "hi bob")]);
+    assert!(rooms[1].chat_log.is_empty());
+
+    let threads = storage.load_threads().unwrap();
+    let pairs: Vec<&[String]> = threads.iter().map(|thread| thread.users.as_slice()).collect();
+    assert_eq!(pairs, [["a1", "b2"], ["a1", "c3"]]);
+    assert_eq!(said(&threads[0].chat_log), [("b2", "b2's name", "psst"), ("a1", "a1's name", "back")]);
+    assert_eq!(said(&threads[1].chat_log), [("c3", "c3's name", "other")]);
+}
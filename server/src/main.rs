@@ -0,0 +1,60 @@
+// src/main.rs
+
+mod mongo;
+mod sqlite;
+#[cfg(test)]
+mod contract;
+
+use std::{env, net::TcpListener, path::PathBuf, sync::Arc, thread};
+use meow_common::{memory::Memory, serve, storage::{Storage, Volatile}};
+use mongo::Mongo;
+use sqlite::Sqlite;
+
+// Where the Go server listens
+const DEFAULT_LISTEN: &str = ":4343";
+
+// Go's ":4343" means every interface
+fn address(listen: &str) -> String {
+    match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    }
+}
+
+// MEOW_DB names an SQLite file to keep everything in and MEOW_MONGO a MongoDB server,
+// without either it is all forgotten like with the Go server
+fn storage() -> Result<Box<dyn Storage>, String> {
+    let file = env::var_os("MEOW_DB").filter(|path| !path.is_empty());
+    let mongo = env::var("MEOW_MONGO").ok().filter(|uri| !uri.is_empty());
+    match (file, mongo) {
+        (Some(_), Some(_)) => Err("MEOW_DB and MEOW_MONGO are both set, pick one".to_string()),
+        (Some(path), None) => Ok(Box::new(Sqlite::open(&PathBuf::from(path))?)),
+        (None, Some(uri)) => Ok(Box::new(Mongo::open(&uri)?)),
+        (None, None) => Ok(Box::new(Volatile)),
+    }
+}
+
+fn fail(text: &str) -> ! {
+    eprintln!("meow-server: {}", text);
+    std::process::exit(1);
+}
+
+/// The chat API of api/main.go, same routes and same JSON, so the Flask frontend and
+/// meow-cli work against either. MEOW_LISTEN picks the address, MEOW_DB or MEOW_MONGO where to keep things.
+fn main() {
+    let listen = env::var("MEOW_LISTEN").unwrap_or_else(|_| DEFAULT_LISTEN.to_string());
+    let listener = match TcpListener::bind(address(&listen)) {
+        Ok(listener) => listener,
+        Err(error) => fail(&format!("could not listen on {}: {}", listen, error)),
+    };
+
+    let memory = match storage().and_then(Memory::open) {
+        Ok(memory) => Arc::new(memory),
+        Err(problem) => fail(&problem),
+    };
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let memory = memory.clone();
+        thread::spawn(move || serve::streaming(stream, &|request| memory.reply(request)));
+    }
+}
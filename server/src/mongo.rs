@@ -0,0 +1,189 @@
+// src/mongo.rs
+
+use std::time::Duration;
+use mongodb::{
+    bson::{self, doc, Document},
+    options::{ClientOptions, FindOptions, IndexOptions, UpdateOptions},
+    results::UpdateResult,
+    sync::{Client, Collection},
+    IndexModel,
+};
+use meow_common::api::{Chat, Room, Thread, User};
+use meow_common::storage::Storage;
+
+// The database database/mongo.py uses when the URI does not name one
+const DEFAULT_DATABASE: &str = "myDatabase";
+const COLLECTION: &str = "users";
+const ROOMS: &str = "rooms";
+const THREADS: &str = "threads";
+
+// How long to look for a server before giving up, rather than the driver's thirty seconds
+const SELECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps each user as one document in MongoDB, the way the API sends them:
+/// `{name, id, chats: [{chat, user, name}]}`, each room the same way with its `members`, and
+/// the direct messages between two users as `{users: [id, id], chats}`
+pub struct Mongo {
+    users: Collection<Document>,
+    rooms: Collection<Document>,
+    threads: Collection<Document>,
+}
+
+fn failed(error: mongodb::error::Error) -> String {
+    error.to_string()
+}
+
+fn chat_document(chat: &Chat) -> Result<Document, String> {
+    bson::to_document(chat).map_err(|error| error.to_string())
+}
+
+// An update that matched nothing changed nothing, which the routes have to hear about
+fn found(result: UpdateResult, missing: impl FnOnce() -> String) -> Result<(), String> {
+    match result.matched_count {
+        0 => Err(missing()),
+        _ => Ok(()),
+    }
+}
+
+impl Mongo {
+    /// Connects to the server at `uri` and makes sure user ids stay unique
+    pub fn open(uri: &str) -> Result<Mongo, String> {
+        Mongo::connect(uri, None)
+    }
+
+    // Like `open`, in `database` rather than the one the URI names
+    fn connect(uri: &str, database: Option<&str>) -> Result<Mongo, String> {
+        let mut options = ClientOptions::parse(uri).map_err(|error| format!("{}: {}", uri, error))?;
+        options.server_selection_timeout = Some(SELECT_TIMEOUT);
+        options.app_name = Some("meow-server".to_string());
+        let client = Client::with_options(options).map_err(failed)?;
+        let database = match database {
+            Some(name) => client.database(name),
+            None => client.default_database().unwrap_or_else(|| client.database(DEFAULT_DATABASE)),
+        };
+
+        database.run_command(doc! { "ping": 1 }, None).map_err(|error| format!("could not reach MongoDB at {}: {}", uri, error))?;
+        let users = database.collection::<Document>(COLLECTION);
+        let unique = IndexModel::builder().keys(doc! { "id": 1 }).options(IndexOptions::builder().unique(true).build()).build();
+        users.create_index(unique.clone(), None).map_err(failed)?;
+        let rooms = database.collection::<Document>(ROOMS);
+        rooms.create_index(unique, None).map_err(failed)?;
+        let threads = database.collection::<Document>(THREADS);
+        Ok(Mongo { users, rooms, threads })
+    }
+}
+
+impl Storage for Mongo {
+    fn load(&mut self) -> Result<Vec<User>, String> {
+        // Object ids grow with time, so this is the order users signed up in
+        let oldest_first = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut users = Vec::new();
+        for document in self.users.find(None, oldest_first).map_err(failed)? {
+            let document = document.map_err(failed)?;
+            // Whatever else ended up in the collection is left alone
+            match bson::from_document::<User>(document) {
+                Ok(user) => users.push(user),
+                Err(error) => eprintln!("meow-server: skipped a document in {} that is not a user: {}", COLLECTION, error),
+            }
+        }
+        Ok(users)
+    }
+
+    fn add_user(&mut self, user: &User) -> Result<(), String> {
+        let document = bson::to_document(user).map_err(|error| error.to_string())?;
+        self.users.insert_one(document, None).map_err(failed)?;
+        Ok(())
+    }
+
+    fn rename(&mut self, id: &str, name: &str) -> Result<(), String> {
+        let result = self.users.update_one(doc! { "id": id }, doc! { "$set": { "name": name } }, None).map_err(failed)?;
+        found(result, || format!("no user {}", id))
+    }
+
+    fn set_keys(&mut self, id: &str, keys: &str) -> Result<(), String> {
+        let result = self.users.update_one(doc! { "id": id }, doc! { "$set": { "keys": keys } }, None).map_err(failed)?;
+        found(result, || format!("no user {}", id))
+    }
+
+    fn add_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String> {
+        let result = self.users.update_one(doc! { "id": id }, doc! { "$push": { "chats": chat_document(chat)? } }, None).map_err(failed)?;
+        found(result, || format!("no user {}", id))
+    }
+
+    fn set_chat(&mut self, id: &str, index: usize, chat: &Chat) -> Result<(), String> {
+        let place = format!("chats.{}", index);
+        let mut set = Document::new();
+        set.insert(&place, chat_document(chat)?);
+        // Setting past the end would pad the log with nulls, so the chat has to be there already
+        let result = self.users.update_one(doc! { "id": id, place: { "$exists": true } }, doc! { "$set": set }, None).map_err(failed)?;
+        found(result, || format!("no chat {} for user {}", index, id))
+    }
+
+    fn load_rooms(&mut self) -> Result<Vec<Room>, String> {
+        let oldest_first = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut rooms = Vec::new();
+        for document in self.rooms.find(None, oldest_first).map_err(failed)? {
+            match bson::from_document::<Room>(document.map_err(failed)?) {
+                Ok(room) => rooms.push(room),
+                Err(error) => eprintln!("meow-server: skipped a document in {} that is not a room: {}", ROOMS, error),
+            }
+        }
+        Ok(rooms)
+    }
+
+    fn add_room(&mut self, room: &Room) -> Result<(), String> {
+        let document = bson::to_document(room).map_err(|error| error.to_string())?;
+        self.rooms.insert_one(document, None).map_err(failed)?;
+        Ok(())
+    }
+
+    fn set_members(&mut self, id: &str, members: &[String]) -> Result<(), String> {
+        let result = self.rooms.update_one(doc! { "id": id }, doc! { "$set": { "members": members } }, None).map_err(failed)?;
+        found(result, || format!("no room {}", id))
+    }
+
+    fn add_room_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String> {
+        let result = self.rooms.update_one(doc! { "id": id }, doc! { "$push": { "chats": chat_document(chat)? } }, None).map_err(failed)?;
+        found(result, || format!("no room {}", id))
+    }
+
+    fn load_threads(&mut self) -> Result<Vec<Thread>, String> {
+        let oldest_first = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut threads = Vec::new();
+        for document in self.threads.find(None, oldest_first).map_err(failed)? {
+            match bson::from_document::<Thread>(document.map_err(failed)?) {
+                Ok(thread) => threads.push(thread),
+                Err(error) => eprintln!("meow-server: skipped a document in {} that is not a thread: {}", THREADS, error),
+            }
+        }
+        Ok(threads)
+    }
+
+    fn add_direct(&mut self, users: &[String], chat: &Chat) -> Result<(), String> {
+        // The first message between two users starts their thread
+        let upsert = UpdateOptions::builder().upsert(true).build();
+        self.threads.update_one(doc! { "users": users }, doc! { "$push": { "chats": chat_document(chat)? } }, upsert).map_err(failed)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+    use crate::contract;
+
+    // Runs against the MongoDB server in MEOW_MONGO, in a database of its own that is dropped
+    // afterwards. Run it with `cargo test -- --ignored` once MEOW_MONGO points at one.
+    #[test]
+    #[ignore = "needs MEOW_MONGO"]
+    fn storage_round_trip() {
+        let uri = env::var("MEOW_MONGO").expect("MEOW_MONGO should point at a MongoDB server");
+        let database = format!("meow-test-{}", std::process::id());
+        let open = || -> Box<dyn Storage> { Box::new(Mongo::connect(&uri, Some(&database)).unwrap()) };
+        let client = Client::with_uri_str(&uri).unwrap();
+        client.database(&database).drop(None).unwrap();
+        contract::round_trip(open(), open);
+        client.database(&database).drop(None).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+// src/sqlite.rs
+
+use std::{collections::HashMap, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use rusqlite::{params, Connection, ErrorCode, Transaction};
+use meow_common::api::{Chat, Room, Thread, User};
+use meow_common::storage::Storage;
+
+// Each step brings the schema up from the version before it, the database remembers how many
+// it has had in user_version. Only ever add to the end.
+const MIGRATIONS: &[&str] = &[
+    // 1: users in the order they signed up, and each one's chat log
+    "CREATE TABLE users (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE chats (
+        owner TEXT NOT NULL REFERENCES users(id),
+        position INTEGER NOT NULL,
+        chat TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        PRIMARY KEY (owner, position)
+    );",
+    // 2: rooms, who is in each and what was said there
+    "CREATE TABLE rooms (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE members (
+        room TEXT NOT NULL REFERENCES rooms(id),
+        position INTEGER NOT NULL,
+        user_id TEXT NOT NULL,
+        PRIMARY KEY (room, position)
+    );
+    CREATE TABLE room_chats (
+        room TEXT NOT NULL REFERENCES rooms(id),
+        position INTEGER NOT NULL,
+        chat TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        PRIMARY KEY (room, position)
+    );",
+    // 3: direct messages, one log per pair of users with the lower id first
+    "CREATE TABLE direct_chats (
+        first TEXT NOT NULL,
+        second TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        chat TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        user_name TEXT NOT NULL,
+        PRIMARY KEY (first, second, position)
+    );",
+    // 4: the key announcement each user published, if any
+    "ALTER TABLE users ADD COLUMN keys TEXT;",
+];
+
+/// Keeps users, rooms, direct messages and chats in an SQLite file, so they outlive the server
+pub struct Sqlite {
+    connection: Connection,
+}
+
+// Opening can fail because the file is not a healthy database, which starting over fixes,
+// or for reasons starting over would not fix
+enum Failure {
+    Damaged(String),
+    Fatal(String),
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(error: rusqlite::Error) -> Failure {
+        match error.sqlite_error_code() {
+            Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt) => Failure::Damaged(error.to_string()),
+            _ => Failure::Fatal(error.to_string()),
+        }
+    }
+}
+
+impl Sqlite {
+    /// Opens the database at `path`, creating or migrating it as needed. A damaged file is
+    /// moved aside and a fresh one started in its place.
+    pub fn open(path: &Path) -> Result<Sqlite, String> {
+        match Sqlite::try_open(path) {
+            Ok(sqlite) => Ok(sqlite),
+            Err(Failure::Fatal(problem)) => Err(format!("{}: {}", path.display(), problem)),
+            Err(Failure::Damaged(problem)) => {
+                let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+                let aside = format!("{}.damaged-{}", path.display(), stamp);
+                fs::rename(path, &aside).map_err(|error| format!("{} is damaged ({}) and could not be moved aside: {}", path.display(), problem, error))?;
+                // The journal belongs to the damaged file and must not be played into the new one
+                for sidecar in ["-wal", "-shm"] {
+                    let _ = fs::rename(format!("{}{}", path.display(), sidecar), format!("{}{}", aside, sidecar));
+                }
+                eprintln!("meow-server: {} was damaged ({}), moved it to {} and started over", path.display(), problem, aside);
+                Sqlite::try_open(path).map_err(|(Failure::Damaged(problem) | Failure::Fatal(problem))| format!("{}: {}", path.display(), problem))
+            }
+        }
+    }
+
+    fn try_open(path: &Path) -> Result<Sqlite, Failure> {
+        let connection = Connection::open(path)?;
+        // A write cut short by a crash is rolled back from the journal the next time the file is opened
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        let check: String = connection.pragma_query_value(None, "quick_check", |row| row.get(0))?;
+        if check != "ok" {
+            return Err(Failure::Damaged(check));
+        }
+
+        let mut sqlite = Sqlite { connection };
+        sqlite.migrate()?;
+        Ok(sqlite)
+    }
+
+    fn migrate(&mut self) -> Result<(), Failure> {
+        let version: usize = self.connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(Failure::Fatal(format!("made by a newer meow-server (schema {}, this one knows {})", version, MIGRATIONS.len())));
+        }
+        for (step, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = self.connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", step + 1)?;
+            transaction.commit()?;
+        }
+        Ok(())
+    }
+}
+
+fn failed(error: rusqlite::Error) -> String {
+    error.to_string()
+}
+
+// Replaces who is in a room, as part of a bigger change
+fn write_members(transaction: &Transaction, id: &str, members: &[String]) -> Result<(), String> {
+    transaction.execute("DELETE FROM members WHERE room = ?1", params![id]).map_err(failed)?;
+    for (position, member) in members.iter().enumerate() {
+        transaction.execute("INSERT INTO members (room, position, user_id) VALUES (?1, ?2, ?3)", params![id, position, member]).map_err(failed)?;
+    }
+    Ok(())
+}
+
+impl Storage for Sqlite {
+    fn load(&mut self) -> Result<Vec<User>, String> {
+        let mut users = Vec::new();
+        let mut places = HashMap::new();
+        let mut query = self.connection.prepare("SELECT id, name, keys FROM users ORDER BY rowid").map_err(failed)?;
+        let rows = query.query_map([], |row| Ok(User { id: row.get(0)?, name: row.get(1)?, chat_log: Vec::new(), keys: row.get(2)? })).map_err(failed)?;
+        for user in rows {
+            let user = user.map_err(failed)?;
+            places.insert(user.id.clone(), users.len());
+            users.push(user);
+        }
+
+        let mut query = self.connection.prepare("SELECT owner, chat, user_id, user_name FROM chats ORDER BY owner, position").map_err(failed)?;
+        let rows = query
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, Chat { chat: row.get(1)?, user_id: row.get(2)?, user_name: row.get(3)? })))
+            .map_err(failed)?;
+        for row in rows {
+            let (owner, chat) = row.map_err(failed)?;
+            if let Some(place) = places.get(&owner) {
+                users[*place].chat_log.push(chat);
+            }
+        }
+        Ok(users)
+    }
+
+    fn add_user(&mut self, user: &User) -> Result<(), String> {
+        let transaction = self.connection.transaction().map_err(failed)?;
+        transaction.execute("INSERT INTO users (id, name, keys) VALUES (?1, ?2, ?3)", params![user.id, user.name, user.keys]).map_err(failed)?;
+        for (position, chat) in user.chat_log.iter().enumerate() {
+            transaction
+                .execute(
+                    "INSERT INTO chats (owner, position, chat, user_id, user_name) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![user.id, position, chat.chat, chat.user_id, chat.user_name],
+                )
+                .map_err(failed)?;
+        }
+        transaction.commit().map_err(failed)
+    }
+
+    fn rename(&mut self, id: &str, name: &str) -> Result<(), String> {
+        match self.connection.execute("UPDATE users SET name = ?2 WHERE id = ?1", params![id, name]).map_err(failed)? {
+            0 => Err(format!("no user {}", id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_keys(&mut self, id: &str, keys: &str) -> Result<(), String> {
+        match self.connection.execute("UPDATE users SET keys = ?2 WHERE id = ?1", params![id, keys]).map_err(failed)? {
+            0 => Err(format!("no user {}", id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn add_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String> {
+        let transaction = self.connection.transaction().map_err(failed)?;
+        let next: Option<usize> =
+            transaction.query_row("SELECT MAX(position) + 1 FROM chats WHERE owner = ?1", params![id], |row| row.get(0)).map_err(failed)?;
+        transaction
+            .execute(
+                "INSERT INTO chats (owner, position, chat, user_id, user_name) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, next.unwrap_or(0), chat.chat, chat.user_id, chat.user_name],
+            )
+            .map_err(failed)?;
+        transaction.commit().map_err(failed)
+    }
+
+    fn set_chat(&mut self, id: &str, index: usize, chat: &Chat) -> Result<(), String> {
+        let changed = self
+            .connection
+            .execute(
+                "UPDATE chats SET chat = ?3, user_id = ?4, user_name = ?5 WHERE owner = ?1 AND position = ?2",
+                params![id, index, chat.chat, chat.user_id, chat.user_name],
+            )
+            .map_err(failed)?;
+        match changed {
+            0 => Err(format!("no chat {} for user {}", index, id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_rooms(&mut self) -> Result<Vec<Room>, String> {
+        let mut rooms = Vec::new();
+        let mut places = HashMap::new();
+        let mut query = self.connection.prepare("SELECT id, name FROM rooms ORDER BY rowid").map_err(failed)?;
+        let rows = query.query_map([], |row| Ok(Room { id: row.get(0)?, name: row.get(1)?, members: Vec::new(), chat_log: Vec::new() })).map_err(failed)?;
+        for room in rows {
+            let room = room.map_err(failed)?;
+            places.insert(room.id.clone(), rooms.len());
+            rooms.push(room);
+        }
+
+        let mut query = self.connection.prepare("SELECT room, user_id FROM members ORDER BY room, position").map_err(failed)?;
+        let rows = query.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))).map_err(failed)?;
+        for row in rows {
+            let (room, member) = row.map_err(failed)?;
+            if let Some(place) = places.get(&room) {
+                rooms[*place].members.push(member);
+            }
+        }
+
+        let mut query = self.connection.prepare("SELECT room, chat, user_id, user_name FROM room_chats ORDER BY room, position").map_err(failed)?;
+        let rows = query
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, Chat { chat: row.get(1)?, user_id: row.get(2)?, user_name: row.get(3)? })))
+            .map_err(failed)?;
+        for row in rows {
+            let (room, chat) = row.map_err(failed)?;
+            if let Some(place) = places.get(&room) {
+                rooms[*place].chat_log.push(chat);
+            }
+        }
+        Ok(rooms)
+    }
+
+    fn add_room(&mut self, room: &Room) -> Result<(), String> {
+        // A room is never kept without its first member
+        let transaction = self.connection.transaction().map_err(failed)?;
+        transaction.execute("INSERT INTO rooms (id, name) VALUES (?1, ?2)", params![room.id, room.name]).map_err(failed)?;
+        write_members(&transaction, &room.id, &room.members)?;
+        transaction.commit().map_err(failed)
+    }
+
+    fn set_members(&mut self, id: &str, members: &[String]) -> Result<(), String> {
+        let transaction = self.connection.transaction().map_err(failed)?;
+        // Emptying a room that is not there would otherwise touch no rows and go unnoticed
+        let rooms: usize = transaction.query_row("SELECT COUNT(*) FROM rooms WHERE id = ?1", params![id], |row| row.get(0)).map_err(failed)?;
+        if rooms == 0 {
+            return Err(format!("no room {}", id));
+        }
+        write_members(&transaction, id, members)?;
+        transaction.commit().map_err(failed)
+    }
+
+    fn add_room_chat(&mut self, id: &str, chat: &Chat) -> Result<(), String> {
+        let transaction = self.connection.transaction().map_err(failed)?;
+        let next: Option<usize> =
+            transaction.query_row("SELECT MAX(position) + 1 FROM room_chats WHERE room = ?1", params![id], |row| row.get(0)).map_err(failed)?;
+        transaction
+            .execute(
+                "INSERT INTO room_chats (room, position, chat, user_id, user_name) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, next.unwrap_or(0), chat.chat, chat.user_id, chat.user_name],
+            )
+            .map_err(failed)?;
+        transaction.commit().map_err(failed)
+    }
+
+    fn load_threads(&mut self) -> Result<Vec<Thread>, String> {
+        let mut threads: Vec<Thread> = Vec::new();
+        // Threads come out in the order they were started
+        let mut query = self
+            .connection
+            .prepare("SELECT first, second, chat, user_id, user_name FROM direct_chats ORDER BY MIN(rowid) OVER (PARTITION BY first, second), position")
+            .map_err(failed)?;
+        let rows = query
+            .query_map([], |row| Ok((vec![row.get(0)?, row.get(1)?], Chat { chat: row.get(2)?, user_id: row.get(3)?, user_name: row.get(4)? })))
+            .map_err(failed)?;
+        for row in rows {
+            let (users, chat) = row.map_err(failed)?;
+            match threads.last_mut() {
+                Some(thread) if thread.users == users => thread.chat_log.push(chat),
+                _ => threads.push(Thread { users, chat_log: vec![chat] }),
+            }
+        }
+        Ok(threads)
+    }
+
+    fn add_direct(&mut self, users: &[String], chat: &Chat) -> Result<(), String> {
+        let transaction = self.connection.transaction().map_err(failed)?;
+        let next: Option<usize> = transaction
+            .query_row("SELECT MAX(position) + 1 FROM direct_chats WHERE first = ?1 AND second = ?2", params![users[0], users[1]], |row| row.get(0))
+            .map_err(failed)?;
+        transaction
+            .execute(
+                "INSERT INTO direct_chats (first, second, position, chat, user_id, user_name) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![users[0], users[1], next.unwrap_or(0), chat.chat, chat.user_id, chat.user_name],
+            )
+            .map_err(failed)?;
+        transaction.commit().map_err(failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+    use super::*;
+    use crate::contract;
+
+    // An empty directory of its own for each test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("meow-sqlite-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn version(sqlite: &Sqlite) -> usize {
+        sqlite.connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn storage_round_trip() {
+        let path = scratch("round-trip").join("meow.db");
+        contract::round_trip(Box::new(Sqlite::open(&path).unwrap()), || Box::new(Sqlite::open(&path).unwrap()));
+    }
+
+    #[test]
+    fn older_schemas_are_brought_up_to_date() {
+        let path = scratch("migrate").join("meow.db");
+        {
+            // A database from before rooms, with someone in it
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch(MIGRATIONS[0]).unwrap();
+            connection.pragma_update(None, "user_version", 1).unwrap();
+            connection.execute("INSERT INTO users (id, name) VALUES ('a1', 'alice')", []).unwrap();
+            connection.execute("INSERT INTO chats (owner, position, chat, user_id, user_name) VALUES ('a1', 0, 'hi', 'a1', 'alice')", []).unwrap();
+        }
+
+        let mut sqlite = Sqlite::open(&path).unwrap();
+        assert_eq!(version(&sqlite), MIGRATIONS.len());
+        let users = sqlite.load().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].chat_log[0].chat, "hi");
+        assert!(users[0].keys.is_none());
+        // The tables and columns of the later steps are there to use
+        sqlite.set_keys("a1", "meow-key:2:alice").unwrap();
+        sqlite.add_room(&Room { name: "general".to_string(), id: "r1".to_string(), members: vec!["a1".to_string()], chat_log: Vec::new() }).unwrap();
+        sqlite.add_direct(&Thread::between("a1", "b2"), &users[0].chat_log[0]).unwrap();
+
+        // Opening again has nothing left to do
+        drop(sqlite);
+        let mut sqlite = Sqlite::open(&path).unwrap();
+        assert_eq!(version(&sqlite), MIGRATIONS.len());
+        assert_eq!(sqlite.load_rooms().unwrap().len(), 1);
+        assert_eq!(sqlite.load().unwrap()[0].keys.as_deref(), Some("meow-key:2:alice"));
+    }
+
+    #[test]
+    fn newer_schemas_are_left_alone() {
+        let path = scratch("newer").join("meow.db");
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        assert!(Sqlite::open(&path).err().unwrap().contains("newer meow-server"));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn damaged_files_are_moved_aside() {
+        let dir = scratch("damaged");
+        let path = dir.join("meow.db");
+        let garbage: Vec<u8> = (0..8192u32).map(|index| (index * 7 % 251) as u8).collect();
+        fs::write(&path, &garbage).unwrap();
+
+        let mut sqlite = Sqlite::open(&path).unwrap();
+        assert_eq!(version(&sqlite), MIGRATIONS.len());
+        assert!(sqlite.load().unwrap().is_empty());
+
+        let aside: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().contains("meow.db.damaged-"))
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(fs::read(&aside[0]).unwrap(), garbage);
+    }
+
+    #[test]
+    fn rooms_are_not_kept_without_their_members() {
+        let path = scratch("add-room").join("meow.db");
+        let mut sqlite = Sqlite::open(&path).unwrap();
+        sqlite.connection.execute_batch("CREATE TRIGGER no_members BEFORE INSERT ON members BEGIN SELECT RAISE(ABORT, 'no members'); END;").unwrap();
+
+        let room = Room { name: "general".to_string(), id: "r1".to_string(), members: vec!["a1".to_string()], chat_log: Vec::new() };
+        assert!(sqlite.add_room(&room).is_err());
+        assert!(sqlite.load_rooms().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+// tests/serve.rs
+
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    sync::{Arc, Once},
+    thread,
+};
+use meow_cli::config::Profile;
+use meow_cli::request::{ApiError, Chat, Client, User};
+use meow_common::{memory::Memory, serve};
+
+// Serves a fresh in-memory server on a free port, the way meow-server does, and returns a client for it
+fn start() -> Client {
+    // Logins are kept under MEOW_HOME, which should not be the real one
+    static HOME: Once = Once::new();
+    HOME.call_once(|| env::set_var("MEOW_HOME", env::temp_dir().join(format!("meow-serve-test-{}", std::process::id()))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let memory = Arc::new(Memory::seeded());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let memory = memory.clone();
+            thread::spawn(move || serve::streaming(stream, &|request| memory.reply(request)));
+        }
+    });
+
+    let profile = Profile {
+        name: format!("serve-test-{}", address.port()),
+        base_url: format!("http://{}", address),
+        ca_file: None,
+        client_cert: None,
+        client_key: None,
+        insecure: false,
+        proxy: None,
+        attached: false,
+    };
+    Client::new(&profile)
+}
+
+fn user(name: &str, id: &str) -> User {
+    User { name: name.to_string(), id: id.to_string(), chat_log: Vec::new(), keys: None }
+}
+
+fn chat(text: &str, from: &User) -> Chat {
+    Chat { chat: text.to_string(), user_id: from.id.clone(), user_name: from.name.clone() }
+}
+
+#[test]
+fn users_and_their_chats() {
+    let client = start();
+    let (alice, bob) = (user("alice", "a1"), user("bob", "b2"));
+    client.register(&alice).unwrap();
+    client.register(&bob).unwrap();
+
+    // The two users the Go server starts with are there as well
+    let names: Vec<String> = client.get_users().unwrap().into_iter().map(|user| user.name).collect();
+    assert_eq!(names, ["awa03", "TestUser3", "alice", "bob"]);
+
+    client.send_chat(&bob.id, &chat("hello bob", &alice)).unwrap();
+    client.send_chat(&bob.id, &chat("again", &alice)).unwrap();
+    let chats = client.get_chats(&bob.id).unwrap();
+    let texts: Vec<&str> = chats.iter().map(|chat| chat.chat.as_str()).collect();
+    assert_eq!(texts, ["hello bob", "again"]);
+    // Like the Go server, a chat is filed under the user it was posted to
+    assert!(chats.iter().all(|chat| chat.user_id == bob.id));
+    assert_eq!(client.get_user(&bob.id).unwrap().chat_log.len(), 2);
+
+    // Signing up again with the same id renames
+    client.register(&user("robert", "b2")).unwrap();
+    assert_eq!(client.get_user(&bob.id).unwrap().name, "robert");
+
+    assert!(matches!(client.get_user("nobody"), Err(ApiError::NotFound)));
+    assert!(matches!(client.send_chat("nobody", &chat("hi", &alice)), Err(ApiError::NotFound)));
+}
+
+#[test]
+fn direct_messages_keep_their_sender() {
+    let client = start();
+    let (alice, bob) = (user("alice", "a1"), user("bob", "b2"));
+    client.register(&alice).unwrap();
+    client.register(&bob).unwrap();
+
+    client.send_direct(&alice.id, &bob.id, &chat("hi bob", &alice)).unwrap();
+    // Whoever the body claims to be, the path says who sent it
+    client.send_direct(&bob.id, &alice.id, &chat("hi alice", &alice)).unwrap();
+
+    let thread = client.get_thread(&bob.id, &alice.id).unwrap();
+    assert_eq!(thread.users, ["a1", "b2"]);
+    let sent: Vec<(&str, &str)> = thread.chat_log.iter().map(|chat| (chat.user_id.as_str(), chat.chat.as_str())).collect();
+    assert_eq!(sent, [("a1", "hi bob"), ("b2", "hi alice")]);
+
+    let listed = client.get_threads(&alice.id).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].length, 2);
+    // Direct messages stay out of the user logs
+    assert!(client.get_chats(&bob.id).unwrap().is_empty());
+}
+
+#[test]
+fn rooms_deliver_to_their_members() {
+    let client = start();
+    let (alice, bob) = (user("alice", "a1"), user("bob", "b2"));
+    client.register(&alice).unwrap();
+    client.register(&bob).unwrap();
+
+    let room = client.create_room("general", &alice.id).unwrap();
+    assert!(matches!(client.send_room_chat("general", &chat("not in yet", &bob)), Err(ApiError::Status(403, _))));
+    client.join_room("general", &bob.id).unwrap();
+    client.send_room_chat(&room.id, &chat("hello room", &bob)).unwrap();
+
+    let room = client.get_room("general").unwrap();
+    assert_eq!(room.members, ["a1", "b2"]);
+    assert_eq!(room.chat_log.len(), 1);
+    assert_eq!(room.chat_log[0].user_name, "bob");
+    assert!(matches!(client.create_room("general", &bob.id), Err(ApiError::Status(409, _))));
+}
+
+#[test]
+fn event_streams_start_after_what_was_seen() {
+    let client = start();
+    let (alice, bob) = (user("alice", "a1"), user("bob", "b2"));
+    client.register(&alice).unwrap();
+    client.register(&bob).unwrap();
+    client.send_chat(&bob.id, &chat("seen", &alice)).unwrap();
+    client.send_chat(&bob.id, &chat("new", &alice)).unwrap();
+
+    let mut lines = BufReader::new(client.subscribe(&bob.id, 1).unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "id: 2");
+    let data = lines.next().unwrap().unwrap();
+    let pushed: Chat = serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+    assert_eq!(pushed.chat, "new");
+}
+
+#[test]
+fn profiles_keep_the_first_keys() {
+    let client = start();
+    client.register(&user("alice", "a1")).unwrap();
+    assert!(client.get_user("a1").unwrap().keys.is_none());
+
+    let keys = |text: &str| User { keys: Some(text.to_string()), ..user("alice", "a1") };
+    client.register(&keys("meow-key:2:first")).unwrap();
+    // Anyone can sign up again with the same id, keys sent later do not replace the first
+    client.register(&keys("meow-key:2:second")).unwrap();
+    client.register(&user("alice", "a1")).unwrap();
+    assert_eq!(client.get_user("a1").unwrap().keys.as_deref(), Some("meow-key:2:first"));
+
+    let listed = client.get_users().unwrap();
+    assert_eq!(listed.iter().find(|user| user.id == "a1").unwrap().keys.as_deref(), Some("meow-key:2:first"));
+}